@@ -1,6 +1,127 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+const MEGABYTE: u64 = 1024 * 1024;
+/// Archives smaller than this are not checked for their compression ratio.
+const RATIO_CHECK_THRESHOLD: u64 = MEGABYTE;
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+/// Enough to read the `ustar` magic of a tar header.
+const MAGIC_LENGTH: usize = 262;
+
+/// Bounds enforced while extracting an uploaded archive.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ExtractLimits {
+    pub max_uncompressed_mb: u64,
+    pub max_files: usize,
+    /// Maximum ratio between the uncompressed and compressed size.
+    pub max_compression_ratio: u64,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_uncompressed_mb: 4096,
+            max_files: 10000,
+            max_compression_ratio: 100,
+        }
+    }
+}
+
+/// Reason an archive was refused, worded for the user who uploaded it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    PathTraversal(String),
+    Symlink(String),
+    TooManyFiles(usize),
+    TooLarge(u64),
+    CompressionRatio(u64, u64),
+    UnknownFormat,
+    FormatDisabled(Format),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::PathTraversal(name) => {
+                write!(f, "{} points outside of the archive", name)
+            }
+            Rejection::Symlink(name) => write!(f, "{} is a symbolic link", name),
+            Rejection::TooManyFiles(limit) => {
+                write!(f, "it contains more than {} files", limit)
+            }
+            Rejection::TooLarge(limit) => {
+                write!(f, "it expands to more than {} MB", limit / MEGABYTE)
+            }
+            Rejection::CompressionRatio(ratio, limit) => write!(
+                f,
+                "it is compressed {}:1, at most {}:1 is allowed",
+                ratio, limit
+            ),
+            Rejection::UnknownFormat => {
+                write!(f, "it is not a zip, tar, tar.gz, tar.zst or 7z archive")
+            }
+            Rejection::FormatDisabled(format) => {
+                write!(f, "{} archives are not supported by this server", format)
+            }
+        }
+    }
+}
+
+/// Container format of an archive, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+    /// Gzip stream, expected to hold a tar archive.
+    TarGz,
+    /// Zstandard stream, expected to hold a tar archive.
+    TarZst,
+    SevenZ,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Zip => write!(f, "zip"),
+            Format::Tar => write!(f, "tar"),
+            Format::TarGz => write!(f, "tar.gz"),
+            Format::TarZst => write!(f, "tar.zst"),
+            Format::SevenZ => write!(f, "7z"),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_magic(header: &[u8]) -> Option<Format> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(Format::Zip)
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Format::TarGz)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Format::TarZst)
+        } else if header.starts_with(b"7z\xbc\xaf\x27\x1c") {
+            Some(Format::SevenZ)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(Format::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<Format, ArchiveError> {
+        let mut header = Vec::with_capacity(MAGIC_LENGTH);
+        File::open(path)?
+            .take(MAGIC_LENGTH as u64)
+            .read_to_end(&mut header)?;
+        Format::from_magic(&header).ok_or(ArchiveError::Rejected(Rejection::UnknownFormat))
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum ArchiveError {
@@ -8,6 +129,17 @@ pub enum ArchiveError {
     CreateError(#[source] zip::result::ZipError),
     #[error("Failed to extract archive: {0}")]
     ExtractError(#[source] zip::result::ZipError),
+    #[cfg(feature = "sevenz")]
+    #[error("Failed to extract 7z archive: {0}")]
+    SevenZError(#[source] sevenz_rust::Error),
+    #[error("Archive rejected: {0}")]
+    Rejected(Rejection),
+    #[error("Duplicate archive entry {0}")]
+    DuplicateEntry(String),
+    #[error("{0} is not inside {1}")]
+    OutsideBase(PathBuf, PathBuf),
+    #[error("Invalid compression level {0}, expected 0 to 9")]
+    InvalidCompressionLevel(i32),
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
@@ -25,28 +157,420 @@ impl Archive {
         self.path.as_path()
     }
 
-    pub fn create<P: AsRef<Path>>(&self, paths: &[P]) -> Result<(), ArchiveError> {
-        let file = File::create(self.as_path())?;
-        let mut zip = zip::ZipWriter::new(file);
+    /// Creates a zip archive of `paths`, named after their path relative to `base`.
+    pub fn create<B: AsRef<Path>, P: AsRef<Path>>(
+        &self,
+        base: B,
+        paths: &[P],
+        compression_level: Option<i32>,
+    ) -> Result<(), ArchiveError> {
+        let base = base.as_ref();
+        let mut writer = ArchiveWriter::new(self.as_path(), compression_level)?;
         for path in paths {
-            let buffer = std::fs::read(&path)?;
-            zip.start_file(
-                // We can unwrap safely here, because we know that the path is a file and is UTF-8 encoded.
-                path.as_ref().file_name().unwrap().to_str().unwrap(),
-                zip::write::FileOptions::default(),
-            )
-            .map_err(ArchiveError::CreateError)?;
-            zip.write_all(&buffer)?;
+            let path = path.as_ref();
+            let relative = path
+                .strip_prefix(base)
+                .map_err(|_| ArchiveError::OutsideBase(path.to_path_buf(), base.to_path_buf()))?;
+            writer.add_file(&entry_name(relative), path)?;
         }
-        Ok(())
+        writer.finish()
+    }
+
+    /// Extracts the archive into `destination`, rejecting entries escaping it,
+    /// symbolic links and archives exceeding `limits`.
+    pub fn extract<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        limits: &ExtractLimits,
+    ) -> Result<(), ArchiveError> {
+        Unpacker::new(destination.as_ref(), limits).add_archive(self, Path::new(""))
     }
 
-    pub fn extract<P: AsRef<Path>>(&self, destination: P) -> Result<(), ArchiveError> {
+    fn extract_zip(&self, extractor: &mut Unpacker) -> Result<(), ArchiveError> {
         let file = File::open(self.as_path())?;
         let mut archive = zip::ZipArchive::new(file).map_err(ArchiveError::ExtractError)?;
-        Ok(archive
-            .extract(destination)
-            .map_err(ArchiveError::ExtractError)?)
+        let mut entries = Vec::with_capacity(archive.len());
+        for index in 0..archive.len() {
+            let entry = archive
+                .by_index_raw(index)
+                .map_err(ArchiveError::ExtractError)?;
+            entries.push((entry.is_dir(), entry.size(), entry.compressed_size()));
+        }
+        extractor.check_declared(entries)?;
+
+        for index in 0..archive.len() {
+            let mut entry = archive
+                .by_index(index)
+                .map_err(ArchiveError::ExtractError)?;
+            let name = entry.name().to_string();
+            let kind = if entry
+                .unix_mode()
+                .is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+            {
+                EntryKind::Link
+            } else if entry.is_dir() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            };
+            extractor.entry(&name, kind, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "sevenz")]
+    fn extract_7z(&self, extractor: &mut Unpacker) -> Result<(), ArchiveError> {
+        /// Set in the Windows attributes when the high 16 bits hold a Unix mode.
+        const UNIX_EXTENSION: u32 = 0x8000;
+
+        let mut reader =
+            sevenz_rust::SevenZReader::open(self.as_path(), sevenz_rust::Password::empty())
+                .map_err(ArchiveError::SevenZError)?;
+        let entries = reader
+            .archive()
+            .files
+            .iter()
+            .map(|entry| (entry.is_directory(), entry.size(), 0))
+            .collect();
+        extractor.check_declared(entries)?;
+
+        let mut failure = None;
+        reader
+            .for_each_entries(|entry, reader| {
+                let attributes = entry.windows_attributes();
+                let kind = if entry.has_windows_attributes
+                    && attributes & UNIX_EXTENSION != 0
+                    && (attributes >> 16) & S_IFMT == S_IFLNK
+                {
+                    EntryKind::Link
+                } else if entry.is_directory() {
+                    EntryKind::Directory
+                } else {
+                    EntryKind::File
+                };
+                match extractor.entry(entry.name(), kind, reader) {
+                    Ok(()) => Ok(true),
+                    Err(error) => {
+                        failure = Some(error);
+                        Ok(false)
+                    }
+                }
+            })
+            .map_err(ArchiveError::SevenZError)?;
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(feature = "tar")]
+fn extract_tar<R: Read>(reader: R, extractor: &mut Unpacker) -> Result<(), ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.display().to_string();
+        let entry_type = entry.header().entry_type();
+        let kind = if entry_type.is_symlink() || entry_type.is_hard_link() {
+            EntryKind::Link
+        } else if entry_type.is_dir() {
+            EntryKind::Directory
+        } else if entry_type.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Other
+        };
+        extractor.entry(&name, kind, &mut entry)?;
+    }
+    Ok(())
+}
+
+enum EntryKind {
+    File,
+    Directory,
+    Link,
+    /// Metadata or special files, skipped.
+    #[cfg_attr(not(feature = "tar"), allow(dead_code))]
+    Other,
+}
+
+/// Gathers archives, directories and loose files below `destination`, enforcing
+/// the limits over all of them.
+pub struct Unpacker {
+    destination: PathBuf,
+    limits: ExtractLimits,
+    /// Subdirectory of `destination` receiving the current input.
+    prefix: PathBuf,
+    /// Size of the inputs, used for the compression ratio.
+    compressed: u64,
+    files: usize,
+    written: u64,
+}
+
+impl Unpacker {
+    pub fn new<P: Into<PathBuf>>(destination: P, limits: &ExtractLimits) -> Self {
+        Unpacker {
+            destination: destination.into(),
+            limits: limits.clone(),
+            prefix: PathBuf::new(),
+            compressed: 0,
+            files: 0,
+            written: 0,
+        }
+    }
+
+    /// Extracts `archive` below `prefix`, whatever its format.
+    pub fn add_archive(&mut self, archive: &Archive, prefix: &Path) -> Result<(), ArchiveError> {
+        let format = Format::detect(archive.as_path())?;
+        self.prefix = prefix.to_path_buf();
+        self.compressed += fs::metadata(archive.as_path())?.len();
+        match format {
+            Format::Zip => archive.extract_zip(self),
+            #[cfg(feature = "tar")]
+            Format::Tar => extract_tar(File::open(archive.as_path())?, self),
+            #[cfg(feature = "gzip")]
+            Format::TarGz => extract_tar(
+                flate2::read::GzDecoder::new(File::open(archive.as_path())?),
+                self,
+            ),
+            #[cfg(feature = "zstd")]
+            Format::TarZst => extract_tar(
+                zstd::stream::read::Decoder::new(File::open(archive.as_path())?)?,
+                self,
+            ),
+            #[cfg(feature = "sevenz")]
+            Format::SevenZ => archive.extract_7z(self),
+            #[allow(unreachable_patterns)]
+            format => Err(ArchiveError::Rejected(Rejection::FormatDisabled(format))),
+        }
+    }
+
+    /// Copies the files of `directory` below `prefix`, keeping their relative paths.
+    pub fn add_directory(&mut self, directory: &Path, prefix: &Path) -> Result<(), ArchiveError> {
+        self.prefix = prefix.to_path_buf();
+        for entry in walkdir::WalkDir::new(directory).min_depth(1) {
+            let entry = entry.map_err(io::Error::from)?;
+            let name = entry
+                .path()
+                .strip_prefix(directory)
+                .unwrap_or(entry.path())
+                .display()
+                .to_string();
+            let file_type = entry.file_type();
+            if file_type.is_symlink() {
+                self.entry(&name, EntryKind::Link, &mut io::empty())?;
+            } else if file_type.is_dir() {
+                self.entry(&name, EntryKind::Directory, &mut io::empty())?;
+            } else {
+                self.compressed += entry.metadata().map_err(io::Error::from)?.len();
+                self.entry(&name, EntryKind::File, &mut File::open(entry.path())?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies a single file at the top of the destination, renaming it when the
+    /// name is already taken.
+    pub fn add_file(&mut self, file: &Path) -> Result<(), ArchiveError> {
+        self.prefix = PathBuf::new();
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut unique = name.clone();
+        let mut index = 1;
+        while self.destination.join(&unique).exists() {
+            unique = format!("{}-{}", index, name);
+            index += 1;
+        }
+        self.compressed += fs::metadata(file)?.len();
+        self.entry(&unique, EntryKind::File, &mut File::open(file)?)
+    }
+
+    /// A limit too large to count in bytes is no limit.
+    fn max_bytes(&self) -> u64 {
+        self.limits.max_uncompressed_mb.saturating_mul(MEGABYTE)
+    }
+
+    /// Rejects early from the `(is_dir, size, compressed_size)` listed in the
+    /// archive index, before anything is written.
+    fn check_declared(&self, entries: Vec<(bool, u64, u64)>) -> Result<(), ArchiveError> {
+        let files = entries.iter().filter(|(is_dir, _, _)| !is_dir).count();
+        if self.files + files > self.limits.max_files {
+            return Err(ArchiveError::Rejected(Rejection::TooManyFiles(
+                self.limits.max_files,
+            )));
+        }
+        let declared = entries.iter().fold(self.written, |total, (_, size, _)| {
+            total.saturating_add(*size)
+        });
+        if declared > self.max_bytes() {
+            return Err(ArchiveError::Rejected(Rejection::TooLarge(
+                self.max_bytes(),
+            )));
+        }
+        self.check_ratio(declared)
+    }
+
+    fn check_ratio(&self, uncompressed: u64) -> Result<(), ArchiveError> {
+        let ratio = uncompressed / self.compressed.max(1);
+        if uncompressed > RATIO_CHECK_THRESHOLD && ratio > self.limits.max_compression_ratio {
+            return Err(ArchiveError::Rejected(Rejection::CompressionRatio(
+                ratio,
+                self.limits.max_compression_ratio,
+            )));
+        }
+        Ok(())
+    }
+
+    fn entry(
+        &mut self,
+        name: &str,
+        kind: EntryKind,
+        reader: &mut dyn Read,
+    ) -> Result<(), ArchiveError> {
+        let path = match enclosed_path(name) {
+            Some(relative) => self.destination.join(&self.prefix).join(relative),
+            None => {
+                return Err(ArchiveError::Rejected(Rejection::PathTraversal(
+                    name.to_string(),
+                )))
+            }
+        };
+        match kind {
+            EntryKind::Link => Err(ArchiveError::Rejected(Rejection::Symlink(name.to_string()))),
+            EntryKind::Other => Ok(()),
+            EntryKind::Directory => Ok(fs::create_dir_all(path)?),
+            EntryKind::File => {
+                self.files += 1;
+                if self.files > self.limits.max_files {
+                    return Err(ArchiveError::Rejected(Rejection::TooManyFiles(
+                        self.limits.max_files,
+                    )));
+                }
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // The declared sizes may lie, so count what is actually decompressed.
+                let remaining = self.max_bytes() - self.written;
+                let mut output = File::create(&path)?;
+                let copied = io::copy(&mut reader.take(remaining.saturating_add(1)), &mut output)?;
+                if copied > remaining {
+                    return Err(ArchiveError::Rejected(Rejection::TooLarge(
+                        self.max_bytes(),
+                    )));
+                }
+                self.written += copied;
+                self.check_ratio(self.written)
+            }
+        }
+    }
+}
+
+/// Relative path of an entry, `None` when it is absolute or climbs out of the
+/// destination.
+fn enclosed_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Zip entry name of `relative`, always `/` separated.
+fn entry_name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Deflate options for `level`, `0` storing entries uncompressed and `None`
+/// using the default level.
+fn compression_options(level: Option<i32>) -> Result<zip::write::FileOptions, ArchiveError> {
+    let options = zip::write::FileOptions::default();
+    match level {
+        None => Ok(options.compression_method(zip::CompressionMethod::Deflated)),
+        Some(0) => Ok(options.compression_method(zip::CompressionMethod::Stored)),
+        Some(level @ 1..=9) => Ok(options
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(Some(level))),
+        Some(level) => Err(ArchiveError::InvalidCompressionLevel(level)),
+    }
+}
+
+/// Checks a configured compression level before any archive is written.
+pub fn validate_compression_level(level: Option<i32>) -> Result<(), ArchiveError> {
+    compression_options(level).map(|_| ())
+}
+
+/// Zip archive written entry by entry, streaming file contents. Archives built
+/// concurrently, e.g. one per thread, can be merged with `append` without
+/// compressing their entries again.
+pub struct ArchiveWriter {
+    zip: zip::ZipWriter<BufWriter<File>>,
+    options: zip::write::FileOptions,
+    names: HashSet<String>,
+}
+
+impl ArchiveWriter {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        compression_level: Option<i32>,
+    ) -> Result<ArchiveWriter, ArchiveError> {
+        let options = compression_options(compression_level)?;
+        Ok(ArchiveWriter {
+            zip: zip::ZipWriter::new(BufWriter::new(File::create(path)?)),
+            options,
+            names: HashSet::new(),
+        })
+    }
+
+    /// Adds the content of `reader` as `name`, failing if `name` is already taken.
+    pub fn add_reader<R: Read>(&mut self, name: &str, reader: &mut R) -> Result<(), ArchiveError> {
+        self.reserve(name)?;
+        self.zip
+            .start_file(name, self.options)
+            .map_err(ArchiveError::CreateError)?;
+        io::copy(reader, &mut self.zip)?;
+        Ok(())
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> Result<(), ArchiveError> {
+        self.add_reader(name, &mut File::open(path)?)
+    }
+
+    /// Copies every entry of the zip `archive` as is.
+    pub fn append(&mut self, archive: &Archive) -> Result<(), ArchiveError> {
+        let mut source = zip::ZipArchive::new(File::open(archive.as_path())?)
+            .map_err(ArchiveError::ExtractError)?;
+        for index in 0..source.len() {
+            let entry = source
+                .by_index_raw(index)
+                .map_err(ArchiveError::ExtractError)?;
+            self.reserve(entry.name())?;
+            self.zip
+                .raw_copy_file(entry)
+                .map_err(ArchiveError::CreateError)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), ArchiveError> {
+        self.zip
+            .finish()
+            .map_err(ArchiveError::CreateError)?
+            .flush()?;
+        Ok(())
+    }
+
+    fn reserve(&mut self, name: &str) -> Result<(), ArchiveError> {
+        if !self.names.insert(name.to_string()) {
+            return Err(ArchiveError::DuplicateEntry(name.to_string()));
+        }
+        Ok(())
     }
 }
 
@@ -77,14 +601,270 @@ mod tests {
         let file_path = target.path().join("test01.txt");
         let mut file = File::create(&file_path).unwrap();
         writeln!(file, "test01").unwrap();
+        fs::create_dir(target.path().join("series")).unwrap();
+        let nested_path = target.path().join("series/test01.txt");
+        fs::write(&nested_path, "nested").unwrap();
         let archive = Archive::new(dest.path().join("test.zip"));
-        let paths = vec![file_path];
-        archive.create(&paths).unwrap();
+        let paths = vec![file_path, nested_path];
+        archive.create(target.path(), &paths, Some(9)).unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(archive.as_path()).unwrap()).unwrap();
+        let mut names: Vec<&str> = zip.file_names().collect();
+        names.sort();
+        assert_eq!(names, vec!["series/test01.txt", "test01.txt"]);
+        assert_eq!(
+            zip.by_name("test01.txt").unwrap().compression(),
+            zip::CompressionMethod::Deflated
+        );
+    }
+
+    #[test]
+    fn test_archive_create_rejects_collisions_and_bad_levels() {
+        let target = tempdir().unwrap();
+        let file_path = target.path().join("slice.dcm");
+        fs::write(&file_path, "slice").unwrap();
+        let archive = Archive::new(target.path().join("out.zip"));
+        assert!(matches!(
+            archive.create(target.path(), &[&file_path, &file_path], None),
+            Err(ArchiveError::DuplicateEntry(name)) if name == "slice.dcm"
+        ));
+        assert!(matches!(
+            archive.create("/elsewhere", &[&file_path], None),
+            Err(ArchiveError::OutsideBase(_, _))
+        ));
+        assert!(matches!(
+            archive.create(target.path(), &[&file_path], Some(10)),
+            Err(ArchiveError::InvalidCompressionLevel(10))
+        ));
+        assert!(validate_compression_level(Some(9)).is_ok());
+        assert!(validate_compression_level(Some(-1)).is_err());
+    }
+
+    #[test]
+    fn test_archive_writer_appends_parts() {
+        let dir = tempdir().unwrap();
+        let parts: Vec<PathBuf> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..2)
+                .map(|part| {
+                    let path = dir.path().join(format!("part{}.zip", part));
+                    scope.spawn(move || {
+                        let mut writer = ArchiveWriter::new(&path, Some(1)).unwrap();
+                        let name = format!("slice{}.dcm", part);
+                        writer.add_reader(&name, &mut "slice".as_bytes()).unwrap();
+                        writer.finish().unwrap();
+                        path
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let mut writer = ArchiveWriter::new(dir.path().join("all.zip"), None).unwrap();
+        for part in &parts {
+            writer.append(&Archive::new(part)).unwrap();
+        }
+        assert!(matches!(
+            writer.append(&Archive::new(&parts[0])),
+            Err(ArchiveError::DuplicateEntry(_))
+        ));
+        writer.finish().unwrap();
+        let zip = zip::ZipArchive::new(File::open(dir.path().join("all.zip")).unwrap()).unwrap();
+        assert_eq!(zip.len(), 2);
+    }
+
+    fn zip_with(entries: &[(&str, &[u8])], options: zip::write::FileOptions) -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        let mut zip = zip::ZipWriter::new(File::create(dir.path().join("upload.zip")).unwrap());
+        for (name, content) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+        dir
+    }
+
+    fn extract(dir: &tempfile::TempDir, limits: &ExtractLimits) -> Result<(), ArchiveError> {
+        let destination = dir.path().join("out");
+        Archive::new(dir.path().join("upload.zip")).extract(destination, limits)
+    }
+
+    #[test]
+    fn test_archive_extract() {
+        let dir = zip_with(
+            &[("series/IM0001", b"dicom")],
+            zip::write::FileOptions::default(),
+        );
+        extract(&dir, &ExtractLimits::default()).unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("out/series/IM0001")).unwrap(),
+            b"dicom"
+        );
+    }
+
+    #[test]
+    fn test_archive_rejects_path_traversal() {
+        let dir = zip_with(&[("../evil", b"x")], zip::write::FileOptions::default());
+        assert!(matches!(
+            extract(&dir, &ExtractLimits::default()),
+            Err(ArchiveError::Rejected(Rejection::PathTraversal(_)))
+        ));
+        assert!(!dir.path().join("evil").exists());
+    }
+
+    #[test]
+    fn test_archive_rejects_symlink() {
+        let dir = tempdir().unwrap();
+        let mut zip = zip::ZipWriter::new(File::create(dir.path().join("upload.zip")).unwrap());
+        zip.add_symlink("link", "/etc/passwd", zip::write::FileOptions::default())
+            .unwrap();
+        zip.finish().unwrap();
+        assert!(matches!(
+            extract(&dir, &ExtractLimits::default()),
+            Err(ArchiveError::Rejected(Rejection::Symlink(_)))
+        ));
+    }
+
+    #[test]
+    fn test_archive_limits() {
+        let dir = zip_with(
+            &[("a", b"1"), ("b", b"2"), ("c", b"3")],
+            zip::write::FileOptions::default(),
+        );
+        let limits = ExtractLimits {
+            max_files: 2,
+            ..ExtractLimits::default()
+        };
+        assert!(matches!(
+            extract(&dir, &limits),
+            Err(ArchiveError::Rejected(Rejection::TooManyFiles(2)))
+        ));
+
+        let zeros = vec![0u8; 2 * MEGABYTE as usize];
+        let dir = zip_with(&[("zeros", &zeros)], zip::write::FileOptions::default());
+        assert!(matches!(
+            extract(&dir, &ExtractLimits::default()),
+            Err(ArchiveError::Rejected(Rejection::CompressionRatio(_, 100)))
+        ));
+        let limits = ExtractLimits {
+            max_uncompressed_mb: 1,
+            max_compression_ratio: u64::MAX,
+            ..ExtractLimits::default()
+        };
+        assert!(matches!(
+            extract(&dir, &limits),
+            Err(ArchiveError::Rejected(Rejection::TooLarge(_)))
+        ));
+        let limits = ExtractLimits {
+            max_uncompressed_mb: u64::MAX,
+            max_compression_ratio: u64::MAX,
+            ..ExtractLimits::default()
+        };
+        assert!(extract(&dir, &limits).is_ok());
+    }
+
+    #[test]
+    fn test_format_from_magic() {
+        assert_eq!(Format::from_magic(b"PK\x03\x04rest"), Some(Format::Zip));
+        assert_eq!(Format::from_magic(&[0x1f, 0x8b, 8]), Some(Format::TarGz));
+        assert_eq!(
+            Format::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Some(Format::TarZst)
+        );
+        assert_eq!(
+            Format::from_magic(b"7z\xbc\xaf\x27\x1c\x00\x04"),
+            Some(Format::SevenZ)
+        );
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(Format::from_magic(&tar), Some(Format::Tar));
+        assert_eq!(Format::from_magic(b"not an archive"), None);
+    }
+
+    #[cfg(feature = "tar")]
+    fn tar_with<W: Write>(writer: W, entries: &[(&str, &[u8])]) -> W {
+        let mut builder = tar::Builder::new(writer);
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_archive_extract_tar_gz() {
+        let dir = tempdir().unwrap();
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(dir.path().join("upload.tgz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        tar_with(encoder, &[("series/IM0001", b"dicom")])
+            .finish()
+            .unwrap();
+        Archive::new(dir.path().join("upload.tgz"))
+            .extract(dir.path().join("out"), &ExtractLimits::default())
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("out/series/IM0001")).unwrap(),
+            b"dicom"
+        );
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_archive_tar_rejects_path_traversal() {
+        let dir = tempdir().unwrap();
+        tar_with(
+            File::create(dir.path().join("upload.tar")).unwrap(),
+            &[("../evil", b"x")],
+        );
+        assert!(matches!(
+            Archive::new(dir.path().join("upload.tar"))
+                .extract(dir.path().join("out"), &ExtractLimits::default()),
+            Err(ArchiveError::Rejected(Rejection::PathTraversal(_)))
+        ));
+        assert!(!dir.path().join("evil").exists());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_archive_extract_tar_zst() {
+        let dir = tempdir().unwrap();
+        let encoder =
+            zstd::stream::write::Encoder::new(File::create(dir.path().join("upload")).unwrap(), 0)
+                .unwrap();
+        tar_with(encoder, &[("IM0001", b"dicom")]).finish().unwrap();
+        Archive::new(dir.path().join("upload"))
+            .extract(dir.path().join("out"), &ExtractLimits::default())
+            .unwrap();
+        assert!(dir.path().join("out/IM0001").is_file());
+    }
+
+    #[cfg(feature = "sevenz")]
+    #[test]
+    fn test_archive_extract_7z() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("in/series")).unwrap();
+        std::fs::write(dir.path().join("in/series/IM0001"), "dicom").unwrap();
+        sevenz_rust::compress_to_path(dir.path().join("in"), dir.path().join("upload.7z")).unwrap();
+        Archive::new(dir.path().join("upload.7z"))
+            .extract(dir.path().join("out"), &ExtractLimits::default())
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("out/series/IM0001")).unwrap(),
+            b"dicom"
+        );
     }
 
     #[test]
     fn test_archive_create_with_invalid_path() {
         let archive = Archive::new("/invalid/path/test.zip");
-        assert!(archive.create::<PathBuf>(&[]).is_err());
+        assert!(archive.create::<_, PathBuf>("/invalid", &[], None).is_err());
     }
 }
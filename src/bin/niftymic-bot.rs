@@ -1,15 +1,43 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use ::config::ConfigError;
 use teloxide::net::Download;
+use teloxide::utils::command::BotCommands;
 use teloxide::{prelude::*, RequestError};
 
+use niftymic_bot::archive::Format;
+use niftymic_bot::cancel::CancellationToken;
 use niftymic_bot::config::Config;
+use niftymic_bot::inventory::Series;
+use niftymic_bot::job::{Job, JobState};
+use niftymic_bot::nifti::{self, Stack};
+use niftymic_bot::progress::{step_label, Progress, ProgressCallback};
+use niftymic_bot::queue::{JobQueue, Ticket};
 use niftymic_bot::*;
-use teloxide::types::{Document, InputFile};
+use teloxide::types::{
+    Document, InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup, InputFile,
+    MessageId,
+};
 use thiserror::Error;
 use tokio::fs;
 
+const PRESET_CALLBACK_PREFIX: &str = "preset:";
+const STACK_CALLBACK_PREFIX: &str = "stack:";
+const STACK_CALLBACK_START: &str = "start";
+/// Minimum delay between two edits of a status message, to stay below Telegram rate limits.
+const STATUS_EDIT_INTERVAL: Duration = Duration::from_secs(5);
+/// Number of output lines of a failed command quoted in the failure message.
+const FAILURE_TAIL_LINES: usize = 5;
+/// Quiet period after the last document of a media group before it is queued.
+const MEDIA_GROUP_DELAY: Duration = Duration::from_secs(3);
+/// Prefix of the temporary directories holding the documents of an upload.
+const UPLOAD_DIRECTORY_PREFIX: &str = "niftymic-upload-";
+/// Time without a new document after which an upload never sent with `/done` is dropped.
+const PENDING_UPLOAD_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Failed to convert: {0}")]
@@ -18,36 +46,813 @@ pub enum Error {
     ConfigurationError(#[from] ConfigError),
 }
 
-async fn start_reconstruction(archive_path: &str) -> Result<String, Error> {
+/// Reconstruction settings chosen with `/preset` and `/set`, applied to the next upload of a chat.
+#[derive(Debug, Clone)]
+struct ChatSettings {
+    preset: Option<String>,
+    options: niftymic::Options,
+}
+
+enum Task {
+    Upload {
+        /// Directory holding the downloaded documents, removed once the job ran.
+        upload_directory: PathBuf,
+        message_id: MessageId,
+        settings: Box<ChatSettings>,
+    },
+    Resume {
+        working_directory: String,
+        preset: Option<String>,
+        /// Status shown while the job runs.
+        notice: &'static str,
+    },
+}
+
+/// What a job run ended with when it did not fail.
+enum Outcome {
+    /// Output archive sent back to the chat.
+    Finished(String),
+    /// Job paused until the user confirms or picks the series to reconstruct.
+    AwaitingSelection(String, Vec<Series>),
+    /// Job paused until the user picks the stacks to reconstruct, with a PNG
+    /// thumbnail of each stack when it could be rendered.
+    AwaitingStacks(String, Vec<Stack>, Vec<Option<Vec<u8>>>),
+}
+
+/// Documents received from a chat that are not queued yet.
+struct PendingUpload {
+    directory: PathBuf,
+    message_id: MessageId,
+    files: usize,
+    media_group: Option<String>,
+    last_update: Instant,
+    /// Documents still downloading, the upload is neither queued nor expired
+    /// until they are received.
+    in_flight: usize,
+}
+
+struct QueuedJob {
+    chat_id: ChatId,
+    task: Task,
+}
+
+#[derive(Clone)]
+struct BotState {
+    bot_name: String,
+    config: Config,
+    chats: Arc<Mutex<HashMap<ChatId, ChatSettings>>>,
+    queue: JobQueue<QueuedJob>,
+    running: Arc<Mutex<HashMap<Ticket, (ChatId, CancellationToken)>>>,
+    pending: Arc<Mutex<HashMap<ChatId, PendingUpload>>>,
+    /// Working directories waiting for `/confirm` or `/pick`, oldest first.
+    awaiting: Arc<Mutex<HashMap<ChatId, Vec<String>>>>,
+}
+
+impl BotState {
+    fn settings(&self, chat_id: ChatId) -> ChatSettings {
+        self.chats
+            .lock()
+            .unwrap()
+            .get(&chat_id)
+            .cloned()
+            .unwrap_or_else(|| ChatSettings {
+                preset: None,
+                options: self.config.reconstruction.clone(),
+            })
+    }
+
+    fn set_settings(&self, chat_id: ChatId, settings: ChatSettings) {
+        self.chats.lock().unwrap().insert(chat_id, settings);
+    }
+
+    fn reset_settings(&self, chat_id: ChatId) {
+        self.chats.lock().unwrap().remove(&chat_id);
+    }
+}
+
+#[derive(BotCommands, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "Send an archive of DICOM files or NIfTI stacks, or the files followed by /done, to start a reconstruction. Commands:"
+)]
+enum Command {
+    #[command(description = "display this text.")]
+    Help,
+    #[command(description = "show the options used for your next reconstruction.")]
+    Options,
+    #[command(
+        description = "set a reconstruction option, e.g. /set resolution 0.5",
+        parse_with = "split"
+    )]
+    Set { key: String, value: String },
+    #[command(description = "pick a named preset, e.g. /preset high-res")]
+    Preset(String),
+    #[command(description = "restore the default reconstruction options.")]
+    Reset,
+    #[command(description = "show the position of your jobs in the queue.")]
+    Queue,
+    #[command(description = "stop your running job and drop your queued ones.")]
+    Cancel,
+    #[command(description = "start a reconstruction from the files sent so far.")]
+    Done,
+    #[command(description = "reconstruct the series or stacks selected so far.")]
+    Confirm,
+    #[command(description = "reconstruct the given series or stacks instead, e.g. /pick 2 4")]
+    Pick(String),
+}
+
+#[derive(Default)]
+struct Status {
+    step: Option<JobState>,
+    converted: usize,
+    cycle: Option<Progress>,
+    iteration: Option<Progress>,
+    last_edit: Option<Instant>,
+    last_text: String,
+    /// A throttled update waits for an edit once `STATUS_EDIT_INTERVAL` is over.
+    flush_scheduled: bool,
+}
+
+impl Status {
+    /// Applies `progress` and tells whether the message should be edited right away.
+    fn update(&mut self, progress: Progress) -> bool {
+        match progress {
+            Progress::Step(step) => {
+                self.step = Some(step);
+                self.cycle = None;
+                self.iteration = None;
+                return true;
+            }
+            Progress::Converted(_) => self.converted += 1,
+            Progress::Cycle { .. } => {
+                self.cycle = Some(progress);
+                self.iteration = None;
+            }
+            Progress::Iteration { .. } => self.iteration = Some(progress),
+        }
+        self.last_edit.map_or(true, |last_edit| {
+            last_edit.elapsed() >= STATUS_EDIT_INTERVAL
+        })
+    }
+
+    fn render(&self) -> String {
+        let mut lines = vec![self
+            .step
+            .map_or("Starting reconstruction ...", step_label)
+            .to_string()];
+        if self.converted > 0 {
+            lines.push(format!("{} series converted", self.converted));
+        }
+        lines.extend(self.cycle.iter().map(|cycle| cycle.to_string()));
+        lines.extend(self.iteration.iter().map(|iteration| iteration.to_string()));
+        lines.join("\n")
+    }
+
+    /// Text of the next edit, `None` when the message shows it already.
+    fn next_edit(&mut self) -> Option<String> {
+        let text = self.render();
+        if text == self.last_text {
+            return None;
+        }
+        self.last_edit = Some(Instant::now());
+        self.last_text = text.clone();
+        Some(text)
+    }
+}
+
+/// Single Telegram message edited in place as the job advances.
+struct StatusMessage {
+    bot: Bot,
+    runtime: tokio::runtime::Handle,
+    chat_id: ChatId,
+    message_id: MessageId,
+    status: Mutex<Status>,
+    /// Held while an edit is sent, so that edits land in order.
+    sending: Mutex<()>,
+}
+
+impl StatusMessage {
+    fn send(
+        bot: &Bot,
+        runtime: &tokio::runtime::Handle,
+        chat_id: ChatId,
+        text: &str,
+    ) -> Result<Arc<StatusMessage>, RequestError> {
+        let message = runtime.block_on(bot.send_message(chat_id, text).send())?;
+        Ok(Arc::new(StatusMessage {
+            bot: bot.clone(),
+            runtime: runtime.clone(),
+            chat_id,
+            message_id: message.id,
+            status: Mutex::default(),
+            sending: Mutex::default(),
+        }))
+    }
+
+    fn update(self: &Arc<Self>, progress: Progress) {
+        let edit_now = {
+            let mut status = self.status.lock().unwrap();
+            let edit_now = status.update(progress);
+            if !edit_now && !status.flush_scheduled {
+                // Shown once the interval is over, even if no progress follows.
+                status.flush_scheduled = true;
+                let delay = status.last_edit.map_or(Duration::ZERO, |last_edit| {
+                    STATUS_EDIT_INTERVAL.saturating_sub(last_edit.elapsed())
+                });
+                let status_message = self.clone();
+                self.runtime.spawn_blocking(move || {
+                    std::thread::sleep(delay);
+                    status_message.status.lock().unwrap().flush_scheduled = false;
+                    status_message.edit();
+                });
+            }
+            edit_now
+        };
+        if edit_now {
+            self.edit();
+        }
+    }
+
+    /// Edits the message to the current status. The status stays unlocked while
+    /// the request is sent, progress is not held up by Telegram.
+    fn edit(&self) {
+        let _sending = self.sending.lock().unwrap();
+        let Some(text) = self.status.lock().unwrap().next_edit() else {
+            return;
+        };
+        let request = self
+            .bot
+            .edit_message_text(self.chat_id, self.message_id, text);
+        if let Err(error) = self.runtime.block_on(request.send()) {
+            log::warn!(
+                "Failed to update status of chat {}: {}",
+                self.chat_id,
+                error
+            );
+        }
+    }
+
+    fn callback(self: &Arc<Self>) -> ProgressCallback {
+        let status_message = self.clone();
+        Arc::new(move |progress| status_message.update(progress))
+    }
+}
+
+fn start_reconstruction(
+    upload_directory: &Path,
+    chat_id: ChatId,
+    settings: ChatSettings,
+    cancel: CancellationToken,
+    progress: Option<ProgressCallback>,
+) -> Result<Outcome, Error> {
     let config = Config::new(None)?;
-    let niftymic = niftymic::NiftyMic::new(&archive_path, &config)?;
+    let mut inputs = Vec::new();
+    for entry in std::fs::read_dir(upload_directory).map_err(niftymic::Error::from)? {
+        let entry = entry.map_err(niftymic::Error::from)?;
+        inputs.push(entry.path().display().to_string());
+    }
+    inputs.sort();
+    let mut niftymic = niftymic::NiftyMic::from_inputs(&inputs, &config)?;
+    niftymic.set_cancellation_token(cancel);
+    if let Some(progress) = progress {
+        niftymic.set_progress_callback(progress);
+    }
+    niftymic.working_directory().update_job(|job| {
+        job.chat_id = Some(chat_id.0);
+        job.preset = settings.preset.clone();
+    })?;
 
-    niftymic.convert_dicom_to_nifti()?;
-    niftymic.generate_masks_from_nifti()?;
-    niftymic.reconstruct(niftymic::Options::default())?;
-    let output_dicom = niftymic.convert_nifti_to_dicom()?;
-    Ok(output_dicom)
+    let job = niftymic.run_until(&settings.options, JobState::Inspected)?;
+    if !job.series.is_empty() {
+        niftymic
+            .working_directory()
+            .update_job(|job| job.awaiting_selection = true)?;
+        return Ok(Outcome::AwaitingSelection(
+            niftymic.working_directory().path.display().to_string(),
+            job.series,
+        ));
+    }
+    continue_reconstruction(&niftymic, &settings.options)
 }
 
-async fn handle_document(
+/// Runs the job to the end, pausing after the conversion for the user to pick
+/// among several stacks unless they were picked already.
+fn continue_reconstruction(
+    niftymic: &niftymic::NiftyMic,
+    options: &niftymic::Options,
+) -> Result<Outcome, Error> {
+    let job = niftymic.run_until(options, JobState::Converted)?;
+    if job.completed == JobState::Converted && job.stacks.is_none() {
+        let working_directory = niftymic.working_directory();
+        // Outside of `run_until`, failures are recorded here so that a restart
+        // does not resume the job into the same error.
+        let paused = working_directory.stacks().and_then(|stacks| {
+            if stacks.len() > 1 {
+                working_directory.update_job(|job| job.awaiting_selection = true)?;
+            }
+            Ok(stacks)
+        });
+        let stacks = match paused {
+            Ok(stacks) => stacks,
+            Err(error) => {
+                working_directory.update_job(|job| job.fail(&error.to_string()))?;
+                return Err(error.into());
+            }
+        };
+        if stacks.len() > 1 {
+            let thumbnails = working_directory
+                .files
+                .nii()
+                .nifti_files()
+                .iter()
+                .map(|image| match nifti::thumbnail(image) {
+                    Ok(thumbnail) => Some(thumbnail),
+                    Err(error) => {
+                        log::warn!("No thumbnail of {}: {}", image, error);
+                        None
+                    }
+                })
+                .collect();
+            return Ok(Outcome::AwaitingStacks(
+                working_directory.path.display().to_string(),
+                stacks,
+                thumbnails,
+            ));
+        }
+    }
+    let output_dicom = niftymic.run(options)?;
+    Ok(Outcome::Finished(output_dicom))
+}
+
+fn resume_reconstruction(
+    working_directory: &str,
+    cancel: CancellationToken,
+    progress: Option<ProgressCallback>,
+) -> Result<Outcome, Error> {
+    let config = Config::new(None)?;
+    let mut niftymic = niftymic::NiftyMic::from_working_directory(working_directory, &config)?;
+    niftymic.set_cancellation_token(cancel);
+    if let Some(progress) = progress {
+        niftymic.set_progress_callback(progress);
+    }
+    let options = niftymic
+        .working_directory()
+        .load_job()?
+        .options
+        .unwrap_or(config.reconstruction.clone());
+    continue_reconstruction(&niftymic, &options)
+}
+
+/// Series found in an upload, the selected ones checked.
+fn selection_message(series: &[Series]) -> String {
+    let mut lines = vec![format!(
+        "Found {} series, the checked ones will be reconstructed:",
+        series.len()
+    )];
+    for (index, series) in series.iter().enumerate() {
+        let mark = if series.selected { "✅" } else { "⬜" };
+        lines.push(format!("{} {}. {}", mark, index + 1, series));
+    }
+    lines.push("Send /confirm to start, or /pick followed by the series numbers.".to_string());
+    lines.join("\n")
+}
+
+/// ULID suffix of a working directory, short enough for callback data.
+fn job_id(working_directory: &niftymic::WorkingDirectory) -> String {
+    working_directory
+        .directory
+        .rsplit_once('-')
+        .map(|(_, ulid)| ulid.to_string())
+        .unwrap_or_else(|| working_directory.directory.clone())
+}
+
+/// Toggle button per stack and a button starting the reconstruction.
+fn stack_keyboard(
+    working_directory: &niftymic::WorkingDirectory,
+    job: &Job,
+    stacks: &[String],
+) -> InlineKeyboardMarkup {
+    let id = job_id(working_directory);
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = stacks
+        .iter()
+        .enumerate()
+        .map(|(index, name)| {
+            let mark = if job.is_stack_selected(name) {
+                "✅"
+            } else {
+                "⬜"
+            };
+            vec![InlineKeyboardButton::callback(
+                format!("{} {}. {}", mark, index + 1, name),
+                format!("{}{}:{}", STACK_CALLBACK_PREFIX, id, index),
+            )]
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback(
+        "Start reconstruction",
+        format!("{}{}:{}", STACK_CALLBACK_PREFIX, id, STACK_CALLBACK_START),
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+fn stack_names(working_directory: &niftymic::WorkingDirectory) -> Vec<String> {
+    working_directory
+        .files
+        .nii()
+        .nifti_files()
+        .iter()
+        .filter_map(|image| Path::new(image).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect()
+}
+
+/// Queues the paused job in `working_directory` once its selection is valid.
+fn queue_selection(
+    state: &BotState,
+    chat_id: ChatId,
+    working_directory: &niftymic::WorkingDirectory,
+) -> String {
+    let job = match working_directory.load_job() {
+        Ok(job) => job,
+        Err(error) => return error.to_string(),
+    };
+    let (selected, kind) = match job.state {
+        JobState::Inspected => (job.selected_series().count(), "series"),
+        _ => (
+            stack_names(working_directory)
+                .iter()
+                .filter(|name| job.is_stack_selected(name))
+                .count(),
+            "stacks",
+        ),
+    };
+    if selected == 0 {
+        return format!("Select at least one of the {} first", kind);
+    }
+    let names = stack_names(working_directory);
+    let confirmed = working_directory.update_job(|job| {
+        // A confirmed default selection must not pause the job again.
+        if job.state == JobState::Converted {
+            job.confirm_stacks(&names);
+        }
+        job.awaiting_selection = false;
+    });
+    if let Err(error) = confirmed {
+        return error.to_string();
+    }
+    if let Some(awaiting) = state.awaiting.lock().unwrap().get_mut(&chat_id) {
+        awaiting.retain(|path| Path::new(path) != working_directory.path);
+    }
+    let (_, position) = state.queue.push(
+        chat_id.0,
+        QueuedJob {
+            chat_id,
+            task: Task::Resume {
+                working_directory: working_directory.path.display().to_string(),
+                preset: job.preset,
+                notice: "Starting reconstruction ...",
+            },
+        },
+    );
+    format!(
+        "Reconstructing {} {}, you are #{} in line",
+        selected, kind, position
+    )
+}
+
+/// Applies `picks`, series or stack numbers depending on the step the job is
+/// paused at, to the oldest job of `chat_id` waiting for a selection and queues it.
+fn resume_selection(state: &BotState, chat_id: ChatId, picks: Option<&str>) -> String {
+    let Some(working_directory) = state
+        .awaiting
+        .lock()
+        .unwrap()
+        .get(&chat_id)
+        .and_then(|awaiting| awaiting.first().cloned())
+    else {
+        return "You have no job waiting for a selection".to_string();
+    };
+    let working_directory = niftymic::WorkingDirectory::new(&working_directory);
+    if let Some(picks) = picks {
+        let picks: Result<Vec<usize>, _> = picks
+            .split([' ', ','])
+            .filter(|pick| !pick.is_empty())
+            .map(str::parse)
+            .collect();
+        let Ok(picks) = picks else {
+            return "Pick by number, e.g. /pick 2 4".to_string();
+        };
+        let picked = match working_directory.load_job().map(|job| job.state) {
+            Ok(JobState::Inspected) => {
+                let mut picked = Ok(());
+                working_directory
+                    .update_job(|job| picked = job.pick_series(&picks))
+                    .and(picked)
+            }
+            Ok(_) => working_directory.pick_stacks(&picks).map(|_| ()),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = picked {
+            return error.to_string();
+        }
+    }
+    queue_selection(state, chat_id, &working_directory)
+}
+
+/// Handles a press on the stack keyboard, `data` being `<job id>:<index or start>`.
+async fn handle_stack_callback(
     bot: &Bot,
-    msg: &Message,
-    document: &Document,
+    message: &Message,
+    state: &BotState,
+    data: &str,
 ) -> Result<(), RequestError> {
-    bot.send_message(msg.chat.id, "Downloading document ...".to_string())
-        .await?;
-    log::debug!("Input document {:?}", document);
-    let file = bot.get_file(&document.file.id).await?;
-    let file_name = document.file_name.clone().unwrap();
-    let archive_path = format!("/tmp/{}", file_name);
-    let mut dst = fs::File::create(&archive_path).await?;
-    bot.download_file(&file.path, &mut dst).await?;
-    bot.send_message(msg.chat.id, "Archive downloaded".to_string())
-        .await?;
-    bot.send_message(msg.chat.id, "Starting reconstruction ...".to_string())
+    let Some((id, action)) = data.split_once(':') else {
+        return Ok(());
+    };
+    let working_directory =
+        match niftymic::WorkingDirectory::find(&state.config.output.base_directory, id) {
+            Ok(working_directory) => working_directory,
+            Err(error) => {
+                bot.edit_message_text(message.chat.id, message.id, error.to_string())
+                    .await?;
+                return Ok(());
+            }
+        };
+    let waiting = state
+        .awaiting
+        .lock()
+        .unwrap()
+        .get(&message.chat.id)
+        .is_some_and(|awaiting| {
+            awaiting
+                .iter()
+                .any(|path| Path::new(path) == working_directory.path)
+        });
+    if !waiting {
+        bot.edit_message_text(
+            message.chat.id,
+            message.id,
+            "This reconstruction already started",
+        )
         .await?;
-    match start_reconstruction(&archive_path.clone()).await {
-        Ok(result) => {
+        return Ok(());
+    }
+    let stacks = stack_names(&working_directory);
+    if action == STACK_CALLBACK_START {
+        let reply = queue_selection(state, message.chat.id, &working_directory);
+        bot.edit_message_text(message.chat.id, message.id, reply)
+            .await?;
+        return Ok(());
+    }
+    let Some(name) = action
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| stacks.get(index))
+    else {
+        return Ok(());
+    };
+    match working_directory.update_job(|job| job.toggle_stack(name, &stacks)) {
+        Ok(job) => {
+            bot.edit_message_reply_markup(message.chat.id, message.id)
+                .reply_markup(stack_keyboard(&working_directory, &job, &stacks))
+                .await?;
+        }
+        Err(error) => {
+            bot.send_message(message.chat.id, error.to_string()).await?;
+        }
+    }
+    Ok(())
+}
+
+fn format_settings(settings: &ChatSettings) -> String {
+    let mut text = format!("preset: {}", settings.preset.as_deref().unwrap_or("none"));
+    for arg in settings.options.to_args() {
+        match arg.strip_prefix("--") {
+            Some(flag) => text.push_str(&format!("\n{}:", flag)),
+            None => text.push_str(&format!(" {}", arg)),
+        }
+    }
+    text
+}
+
+fn select_preset(state: &BotState, chat_id: ChatId, name: &str) -> String {
+    match preset::resolve(name, &state.config) {
+        Ok(options) => {
+            state.set_settings(
+                chat_id,
+                ChatSettings {
+                    preset: Some(name.to_string()),
+                    options,
+                },
+            );
+            format!("Preset {} selected", name)
+        }
+        Err(error) => error.to_string(),
+    }
+}
+
+/// Buttons carry the index of the preset among `preset::names`, a name may be
+/// longer than the 64 bytes Telegram allows as callback data.
+fn preset_keyboard(config: &Config) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(
+        preset::names(config)
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| {
+                vec![InlineKeyboardButton::callback(
+                    name,
+                    format!("{}{}", PRESET_CALLBACK_PREFIX, index),
+                )]
+            }),
+    )
+}
+
+async fn handle_command(
+    bot: &Bot,
+    msg: &Message,
+    command: Command,
+    state: &BotState,
+) -> Result<(), RequestError> {
+    let reply = match command {
+        Command::Help => Command::descriptions().to_string(),
+        Command::Options => format_settings(&state.settings(msg.chat.id)),
+        Command::Set { key, value } => {
+            let mut settings = state.settings(msg.chat.id);
+            match settings.options.set(&key, &value) {
+                Ok(()) => {
+                    // The options no longer match the preset they came from.
+                    settings.preset = None;
+                    state.set_settings(msg.chat.id, settings);
+                    format!("{} set to {}", key, value)
+                }
+                Err(error) => error.to_string(),
+            }
+        }
+        Command::Preset(name) if name.trim().is_empty() => {
+            bot.send_message(msg.chat.id, "Choose a reconstruction preset")
+                .reply_markup(preset_keyboard(&state.config))
+                .await?;
+            return Ok(());
+        }
+        Command::Preset(name) => select_preset(state, msg.chat.id, name.trim()),
+        Command::Reset => {
+            state.reset_settings(msg.chat.id);
+            "Reconstruction options reset to defaults".to_string()
+        }
+        Command::Queue => {
+            let positions = state.queue.positions(msg.chat.id.0);
+            if positions.is_empty() {
+                "You have no job waiting in the queue".to_string()
+            } else {
+                let positions: Vec<String> = positions.iter().map(|p| format!("#{}", p)).collect();
+                format!(
+                    "Your jobs are {} in line out of {}",
+                    positions.join(", "),
+                    state.queue.len()
+                )
+            }
+        }
+        Command::Cancel => cancel_jobs(state, msg.chat.id),
+        Command::Confirm => resume_selection(state, msg.chat.id, None),
+        Command::Pick(picks) => resume_selection(state, msg.chat.id, Some(&picks)),
+        Command::Done => {
+            let pending = {
+                let mut pending = state.pending.lock().unwrap();
+                match pending.get(&msg.chat.id) {
+                    Some(upload) if upload.in_flight > 0 => Err(upload.in_flight),
+                    _ => Ok(pending.remove(&msg.chat.id)),
+                }
+            };
+            match pending {
+                Ok(Some(pending)) => return queue_upload(bot, msg.chat.id, state, pending).await,
+                Ok(None) => "Send DICOM files or an archive first".to_string(),
+                Err(in_flight) => format!(
+                    "{} document(s) still downloading, send /done once they are received",
+                    in_flight
+                ),
+            }
+        }
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+/// Drops the queued jobs of `chat_id` and cancels its running ones.
+fn cancel_jobs(state: &BotState, chat_id: ChatId) -> String {
+    let removed = state.queue.remove_owner(chat_id.0);
+    for job in &removed {
+        if let Task::Upload {
+            upload_directory, ..
+        } = &job.task
+        {
+            remove_upload(upload_directory);
+        }
+    }
+    let pending = state.pending.lock().unwrap().remove(&chat_id);
+    if let Some(pending) = &pending {
+        remove_upload(&pending.directory);
+    }
+    let awaiting = state
+        .awaiting
+        .lock()
+        .unwrap()
+        .remove(&chat_id)
+        .unwrap_or_default();
+    for working_directory in &awaiting {
+        let cancelled = niftymic::WorkingDirectory::new(working_directory)
+            .update_job(|job| job.fail(&niftymic::Error::Cancelled.to_string()));
+        if let Err(error) = cancelled {
+            log::warn!("Failed to cancel {}: {}", working_directory, error);
+        }
+    }
+    let mut cancelled = 0;
+    for (owner, cancel) in state.running.lock().unwrap().values() {
+        if *owner == chat_id {
+            cancel.cancel();
+            cancelled += 1;
+        }
+    }
+    if removed.is_empty() && cancelled == 0 && pending.is_none() && awaiting.is_empty() {
+        return "You have no job to cancel".to_string();
+    }
+    format!(
+        "Cancelled {} running and {} queued job(s)",
+        cancelled,
+        removed.len()
+    )
+}
+
+fn failure_message(error: &Error) -> String {
+    if let Error::FailedToReconstruct(niftymic::Error::ArchiveError(
+        archive::ArchiveError::Rejected(rejection),
+    )) = error
+    {
+        return format!(
+            "Your archive was not processed because {}. Please upload an archive of the DICOM series.",
+            rejection
+        );
+    }
+    let mut message = format!("Failed to reconstruct : {}", error);
+    if let Error::FailedToReconstruct(niftymic::Error::CommandFailed(failure)) = error {
+        let tail = failure.tail(FAILURE_TAIL_LINES);
+        if !tail.is_empty() {
+            message.push_str("\n\n");
+            message.push_str(&tail.join("\n"));
+        }
+    }
+    message
+}
+
+async fn send_result(
+    bot: &Bot,
+    state: &BotState,
+    chat_id: ChatId,
+    result: Result<Outcome, Error>,
+    preset: Option<String>,
+) -> Result<(), RequestError> {
+    match result {
+        Ok(Outcome::AwaitingSelection(working_directory, series)) => {
+            state
+                .awaiting
+                .lock()
+                .unwrap()
+                .entry(chat_id)
+                .or_default()
+                .push(working_directory);
+            bot.send_message(chat_id, selection_message(&series))
+                .await?;
+        }
+        Ok(Outcome::AwaitingStacks(working_directory, stacks, thumbnails)) => {
+            state
+                .awaiting
+                .lock()
+                .unwrap()
+                .entry(chat_id)
+                .or_default()
+                .push(working_directory.clone());
+            for (index, (stack, thumbnail)) in stacks.iter().zip(thumbnails).enumerate() {
+                let caption = format!("{}. {}", index + 1, stack);
+                match thumbnail {
+                    Some(png) => {
+                        let photo = InputFile::memory(png).file_name(format!("{}.png", index + 1));
+                        bot.send_photo(chat_id, photo).caption(caption).await?;
+                    }
+                    None => {
+                        bot.send_message(chat_id, caption).await?;
+                    }
+                }
+            }
+            let working_directory = niftymic::WorkingDirectory::new(&working_directory);
+            let job = working_directory.load_job().unwrap_or_default();
+            let names: Vec<String> = stacks.into_iter().map(|stack| stack.name).collect();
+            bot.send_message(
+                chat_id,
+                "Choose the stacks to reconstruct, or send /pick followed by their numbers",
+            )
+            .reply_markup(stack_keyboard(&working_directory, &job, &names))
+            .await?;
+        }
+        Ok(Outcome::Finished(result)) => {
             let file_name = Path::new(&result)
                 .file_name()
                 .unwrap()
@@ -57,17 +862,386 @@ async fn handle_document(
             let file = fs::File::open(&result).await?;
             let input_file = InputFile::read(file);
             let input_file = input_file.file_name(file_name.clone());
-            bot.send_document(msg.chat.id, input_file).await?;
+            let mut request = bot.send_document(chat_id, input_file);
+            if let Some(preset) = preset {
+                request = request.caption(format!("Preset: {}", preset));
+            }
+            request.await?;
         }
         Err(error) => {
-            bot.send_message(
-                msg.chat.id,
-                format!("Failed to reconstruct : {}", error.to_string()),
+            bot.send_message(chat_id, failure_message(&error)).await?;
+        }
+    };
+    Ok(())
+}
+
+/// Queues jobs interrupted by a previous shutdown, skipping failed ones.
+fn resume_unfinished_jobs(state: &BotState) -> niftymic::Result<()> {
+    for working_directory in niftymic::WorkingDirectory::list(&state.config.output.base_directory)?
+    {
+        let job = working_directory.load_job()?;
+        let Some(chat_id) = job.chat_id else {
+            continue;
+        };
+        if job.is_finished() || job.is_failed() {
+            continue;
+        }
+        if job.awaiting_selection {
+            state
+                .awaiting
+                .lock()
+                .unwrap()
+                .entry(ChatId(chat_id))
+                .or_default()
+                .push(working_directory.path.display().to_string());
+            continue;
+        }
+        log::info!(
+            "Resuming job {} from {:?}",
+            working_directory.directory,
+            job.resume_state()
+        );
+        state.queue.push(
+            chat_id,
+            QueuedJob {
+                chat_id: ChatId(chat_id),
+                task: Task::Resume {
+                    working_directory: working_directory.path.display().to_string(),
+                    preset: job.preset,
+                    notice: "Resuming interrupted reconstruction ...",
+                },
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Runs a queued job on a worker thread and reports back to its chat.
+fn process_job(
+    bot: &Bot,
+    runtime: &tokio::runtime::Handle,
+    state: &BotState,
+    ticket: Ticket,
+    job: QueuedJob,
+) {
+    let chat_id = job.chat_id;
+    let cancel = CancellationToken::new();
+    state
+        .running
+        .lock()
+        .unwrap()
+        .insert(ticket, (chat_id, cancel.clone()));
+    let notify = |text: &str| match StatusMessage::send(bot, runtime, chat_id, text) {
+        Ok(status_message) => Some(status_message.callback()),
+        Err(error) => {
+            log::error!("Failed to notify chat {}: {}", chat_id, error);
+            None
+        }
+    };
+    let (result, preset, message_id) = match job.task {
+        Task::Upload {
+            upload_directory,
+            message_id,
+            settings,
+        } => {
+            let progress = notify("Starting reconstruction ...");
+            let preset = settings.preset.clone();
+            let result =
+                start_reconstruction(&upload_directory, chat_id, *settings, cancel, progress);
+            remove_upload(&upload_directory);
+            (result, preset, Some(message_id))
+        }
+        Task::Resume {
+            working_directory,
+            preset,
+            notice,
+        } => {
+            let progress = notify(notice);
+            (
+                resume_reconstruction(&working_directory, cancel, progress),
+                preset,
+                None,
             )
+        }
+    };
+    state.running.lock().unwrap().remove(&ticket);
+    let sent = runtime.block_on(async {
+        send_result(bot, state, chat_id, result, preset).await?;
+        if let Some(message_id) = message_id {
+            bot.delete_message(chat_id, message_id).await?;
+        }
+        Ok::<(), RequestError>(())
+    });
+    if let Err(error) = sent {
+        log::error!("Failed to send job result to chat {}: {}", chat_id, error);
+    }
+}
+
+fn remove_upload(directory: &Path) {
+    if let Err(error) = std::fs::remove_dir_all(directory) {
+        log::warn!("Failed to remove {}: {}", directory.display(), error);
+    }
+}
+
+/// Removes the upload directories left by a previous run, the uploads they
+/// belonged to only lived in memory.
+fn remove_stale_uploads() {
+    let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let is_upload = entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(UPLOAD_DIRECTORY_PREFIX);
+        if is_upload && entry.path().is_dir() {
+            log::info!("Removing stale upload {}", entry.path().display());
+            remove_upload(&entry.path());
+        }
+    }
+}
+
+/// Drops the pending uploads which received no document for `PENDING_UPLOAD_EXPIRY`.
+async fn expire_pending_uploads(bot: Bot, state: BotState) {
+    loop {
+        tokio::time::sleep(PENDING_UPLOAD_EXPIRY / 4).await;
+        let expired: Vec<(ChatId, PendingUpload)> = {
+            let mut pending = state.pending.lock().unwrap();
+            let chat_ids: Vec<ChatId> = pending
+                .iter()
+                .filter(|(_, upload)| {
+                    upload.in_flight == 0 && upload.last_update.elapsed() >= PENDING_UPLOAD_EXPIRY
+                })
+                .map(|(chat_id, _)| *chat_id)
+                .collect();
+            chat_ids
+                .into_iter()
+                .filter_map(|chat_id| pending.remove(&chat_id).map(|upload| (chat_id, upload)))
+                .collect()
+        };
+        for (chat_id, upload) in expired {
+            remove_upload(&upload.directory);
+            let notice = format!(
+                "Dropped the {} file(s) never sent with /done, send them again",
+                upload.files
+            );
+            if let Err(error) = bot.send_message(chat_id, notice).await {
+                log::error!("Failed to notify chat {}: {}", chat_id, error);
+            }
+        }
+    }
+}
+
+/// Queues the pending documents of `chat_id` as a single job.
+async fn queue_upload(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &BotState,
+    pending: PendingUpload,
+) -> Result<(), RequestError> {
+    let (_, position) = state.queue.push(
+        chat_id.0,
+        QueuedJob {
+            chat_id,
+            task: Task::Upload {
+                upload_directory: pending.directory,
+                message_id: pending.message_id,
+                settings: Box::new(state.settings(chat_id)),
+            },
+        },
+    );
+    bot.send_message(chat_id, format!("You are #{} in line", position))
+        .await?;
+    Ok(())
+}
+
+/// Queues the media group `media_group` once no document was added to it for
+/// `MEDIA_GROUP_DELAY` and none is downloading. Every finished download
+/// schedules this again, the last one queues the group.
+async fn flush_media_group(bot: Bot, chat_id: ChatId, state: BotState, media_group: String) {
+    tokio::time::sleep(MEDIA_GROUP_DELAY).await;
+    let pending = {
+        let mut pending = state.pending.lock().unwrap();
+        match pending.get(&chat_id) {
+            Some(upload)
+                if upload.media_group.as_ref() == Some(&media_group)
+                    && upload.in_flight == 0
+                    && upload.last_update.elapsed() >= MEDIA_GROUP_DELAY =>
+            {
+                pending.remove(&chat_id)
+            }
+            _ => None,
+        }
+    };
+    if let Some(pending) = pending {
+        if let Err(error) = queue_upload(&bot, chat_id, &state, pending).await {
+            log::error!("Failed to queue upload of chat {}: {}", chat_id, error);
+        }
+    }
+}
+
+/// Downloads `document` into `directory`, returning where it was written.
+async fn download_document(
+    bot: &Bot,
+    msg: &Message,
+    document: &Document,
+    directory: &Path,
+) -> Result<PathBuf, RequestError> {
+    fs::create_dir_all(directory).await?;
+    let file = bot.get_file(&document.file.id).await?;
+    // Only keep the last component, the name is chosen by the sender.
+    let file_name = document
+        .file_name
+        .as_deref()
+        .and_then(|name| Path::new(name).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| document.file.unique_id.clone());
+    let path = directory.join(format!("{}-{}", msg.id.0, file_name));
+    let mut dst = fs::File::create(&path).await?;
+    bot.download_file(&file.path, &mut dst).await?;
+    Ok(path)
+}
+
+async fn handle_document(
+    bot: &Bot,
+    msg: &Message,
+    document: &Document,
+    state: &BotState,
+) -> Result<(), RequestError> {
+    let media_group = msg.media_group_id().map(str::to_string);
+    if media_group.is_none() {
+        bot.send_message(msg.chat.id, "Downloading document ...".to_string())
+            .await?;
+    }
+    log::debug!("Input document {:?}", document);
+    let directory = {
+        let mut pending = state.pending.lock().unwrap();
+        let upload = pending.entry(msg.chat.id).or_insert_with(|| PendingUpload {
+            directory: std::env::temp_dir().join(format!(
+                "{}{}",
+                UPLOAD_DIRECTORY_PREFIX,
+                ulid::Ulid::new().to_string().to_lowercase()
+            )),
+            message_id: msg.id,
+            files: 0,
+            media_group: None,
+            last_update: Instant::now(),
+            in_flight: 0,
+        });
+        // Counted before the download starts, so that the upload is not queued
+        // without this document meanwhile.
+        upload.in_flight += 1;
+        upload.directory.clone()
+    };
+    let downloaded = download_document(bot, msg, document, &directory).await;
+
+    let received = {
+        let mut pending = state.pending.lock().unwrap();
+        pending
+            .get_mut(&msg.chat.id)
+            .filter(|upload| upload.directory == directory)
+            .map(|upload| {
+                upload.in_flight -= 1;
+                upload.last_update = Instant::now();
+                upload.media_group = media_group.clone();
+                if downloaded.is_ok() {
+                    upload.files += 1;
+                }
+                (upload.files, upload.in_flight)
+            })
+    };
+    let Some((files, in_flight)) = received else {
+        // Cancelled while downloading, the directory may have been created again
+        // and a later upload of the chat does not own it.
+        remove_upload(&directory);
+        let file_name = document.file_name.as_deref().unwrap_or("the document");
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "The upload was cancelled while {} was downloading, send it again",
+                file_name
+            ),
+        )
+        .await?;
+        return Ok(());
+    };
+    if let Some(media_group) = media_group {
+        // Scheduled even after a failed download, the rest of the group is not held back.
+        tokio::spawn(flush_media_group(
+            bot.clone(),
+            msg.chat.id,
+            state.clone(),
+            media_group,
+        ));
+        downloaded?;
+        return Ok(());
+    }
+    let path = downloaded?;
+    if files == 1 && in_flight == 0 && !filemgr::is_nifti(&path) && Format::detect(&path).is_ok() {
+        bot.send_message(msg.chat.id, "Archive downloaded".to_string())
             .await?;
+        let pending = state.pending.lock().unwrap().remove(&msg.chat.id);
+        if let Some(pending) = pending {
+            queue_upload(bot, msg.chat.id, state, pending).await?;
+        }
+        return Ok(());
+    }
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Received {} file(s). Send more or /done to start the reconstruction.",
+            files
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn handle_message(bot: Bot, msg: Message, state: BotState) -> Result<(), RequestError> {
+    if let Some(document) = msg.document() {
+        handle_document(&bot, &msg, document, &state).await?;
+    } else if let Some(text) = msg.text() {
+        if let Ok(command) = Command::parse(text, &state.bot_name) {
+            handle_command(&bot, &msg, command, &state).await?;
         }
+    }
+    Ok(())
+}
+
+async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+    state: BotState,
+) -> Result<(), RequestError> {
+    bot.answer_callback_query(&query.id).await?;
+    let (Some(data), Some(message)) = (&query.data, &query.message) else {
+        return Ok(());
     };
-    bot.delete_message(msg.chat.id, msg.id).await?;
+    if let Some(index) = data.strip_prefix(PRESET_CALLBACK_PREFIX) {
+        // The presets may have changed since the keyboard was sent, the label of
+        // the button tells which one was meant.
+        let label = message
+            .reply_markup()
+            .into_iter()
+            .flat_map(|markup| markup.inline_keyboard.iter().flatten())
+            .find(|button| {
+                matches!(&button.kind, InlineKeyboardButtonKind::CallbackData(callback) if callback == data)
+            })
+            .map(|button| button.text.as_str());
+        let name = index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| preset::names(&state.config).into_iter().nth(index))
+            .filter(|name| label.map_or(true, |label| label == name));
+        let reply = match name {
+            Some(name) => select_preset(&state, message.chat.id, &name),
+            None => "This preset list is outdated, send /preset again".to_string(),
+        };
+        bot.edit_message_text(message.chat.id, message.id, reply)
+            .await?;
+    } else if let Some(data) = data.strip_prefix(STACK_CALLBACK_PREFIX) {
+        handle_stack_callback(&bot, message, &state, data).await?;
+    }
     Ok(())
 }
 
@@ -75,15 +1249,55 @@ async fn start_bot() -> niftymic::Result<()> {
     log::info!("Starting NiftyMIC_bot ...");
     let config = Config::new(None)?;
 
-    if let Some(telegram) = config.telegram {
+    if let Some(telegram) = config.telegram.clone() {
         let bot = Bot::new(telegram.teloxide_token);
-        teloxide::repl(bot, |bot: Bot, msg: Message| async move {
-            if let Some(document) = msg.document() {
-                handle_document(&bot, &msg, document).await?;
-            }
-            Ok(())
-        })
-        .await;
+        let bot_name = bot
+            .get_me()
+            .await
+            .map_err(|_| niftymic::Error::FailedToStartBot)?
+            .username()
+            .to_string();
+        let state = BotState {
+            bot_name,
+            config,
+            chats: Arc::default(),
+            queue: JobQueue::new(),
+            running: Arc::default(),
+            pending: Arc::default(),
+            awaiting: Arc::default(),
+        };
+        remove_stale_uploads();
+        resume_unfinished_jobs(&state)?;
+        tokio::spawn(expire_pending_uploads(bot.clone(), state.clone()));
+        retention::start_cleaner(&state.config.output.base_directory, &state.config.retention);
+        let worker_bot = bot.clone();
+        let worker_state = state.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let panic_bot = bot.clone();
+        let panic_state = state.clone();
+        let panic_runtime = runtime.clone();
+        state.queue.start_workers(
+            telegram.workers,
+            move |ticket, job| process_job(&worker_bot, &runtime, &worker_state, ticket, job),
+            move |ticket| {
+                let running = panic_state.running.lock().unwrap().remove(&ticket);
+                if let Some((chat_id, _)) = running {
+                    let request = panic_bot.send_message(chat_id, "Job failed unexpectedly");
+                    if let Err(error) = panic_runtime.block_on(request.send()) {
+                        log::error!("Failed to notify chat {}: {}", chat_id, error);
+                    }
+                }
+            },
+        );
+        let handler = dptree::entry()
+            .branch(Update::filter_message().endpoint(handle_message))
+            .branch(Update::filter_callback_query().endpoint(handle_callback_query));
+        Dispatcher::builder(bot, handler)
+            .dependencies(dptree::deps![state])
+            .enable_ctrlc_handler()
+            .build()
+            .dispatch()
+            .await;
         Ok(())
     } else {
         Err(niftymic::Error::FailedToStartBot)
@@ -1,8 +1,17 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use niftymic_bot::cancel::CancellationToken;
 use niftymic_bot::config::Config;
+use niftymic_bot::inventory::Series;
+use niftymic_bot::job::{Job, JobState};
 use niftymic_bot::niftymic::*;
+use niftymic_bot::preset;
+use niftymic_bot::retention;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,55 +30,546 @@ struct Cli {
     command: Commands,
 }
 
+/// Overrides for the `[reconstruction]` section of the configuration
+#[derive(Args)]
+struct ReconstructionArgs {
+    #[arg(long)]
+    alpha: Option<f32>,
+    #[arg(long)]
+    outlier_rejection: Option<u64>,
+    #[arg(long)]
+    threshold_first: Option<f32>,
+    #[arg(long)]
+    threshold: Option<f32>,
+    #[arg(long)]
+    intensity_correction: Option<u64>,
+    #[arg(long)]
+    isotropic_resolution: Option<f32>,
+    #[arg(long)]
+    two_step_cycles: Option<u64>,
+    /// Any other niftymic_reconstruct_volume parameter, e.g. `--set iter-max=20`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    extra: Vec<String>,
+}
+
+impl ReconstructionArgs {
+    fn apply(&self, mut options: Options) -> Result<Options> {
+        if let Some(alpha) = self.alpha {
+            options.alpha = alpha;
+        }
+        if let Some(outlier_rejection) = self.outlier_rejection {
+            options.outlier_rejection = outlier_rejection;
+        }
+        if let Some(threshold_first) = self.threshold_first {
+            options.threshold_first = threshold_first;
+        }
+        if let Some(threshold) = self.threshold {
+            options.threshold = threshold;
+        }
+        if let Some(intensity_correction) = self.intensity_correction {
+            options.intensity_correction = intensity_correction;
+        }
+        if let Some(isotropic_resolution) = self.isotropic_resolution {
+            options.isotropic_resolution = isotropic_resolution;
+        }
+        if let Some(two_step_cycles) = self.two_step_cycles {
+            options.two_step_cycles = two_step_cycles;
+        }
+        for extra in &self.extra {
+            let (key, value) = extra.split_once('=').ok_or_else(|| {
+                Error::InvalidOption(format!("Expected KEY=VALUE, got {}", extra))
+            })?;
+            options.set(key, value)?;
+        }
+        options.validate()?;
+        Ok(options)
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    ConvertDicom { archive_path: String },
-    Pipeline { archive_path: String },
-    GenerateMasks { working_directory: String },
-    Reconstruct { working_directory: String },
-    ConvertNifti { working_directory: String },
+    ConvertDicom {
+        /// Archives, DICOM directories (e.g. with a DICOMDIR) or DICOM files, or NIfTI stacks (.nii, .nii.gz)
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+    Pipeline {
+        /// Archives, DICOM directories (e.g. with a DICOMDIR) or DICOM files, or NIfTI stacks (.nii, .nii.gz)
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        /// Named preset used as a base for the reconstruction options
+        #[arg(long)]
+        preset: Option<String>,
+        #[command(flatten)]
+        options: ReconstructionArgs,
+    },
+    /// Resume a pipeline from its first incomplete step
+    Resume {
+        working_directory: String,
+        #[command(flatten)]
+        options: ReconstructionArgs,
+    },
+    GenerateMasks {
+        working_directory: String,
+    },
+    Reconstruct {
+        working_directory: String,
+        #[command(flatten)]
+        options: ReconstructionArgs,
+    },
+    ConvertNifti {
+        working_directory: String,
+    },
+    /// Inspect the working directories under the output base directory
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommand,
+    },
+    /// Remove old working directories according to the `[retention]` policy
+    Gc {
+        /// Only list the directories that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum JobsCommand {
+    List {
+        #[arg(long)]
+        status: Option<StatusFilter>,
+        /// Only jobs created at least this many days ago
+        #[arg(long, value_name = "DAYS")]
+        older_than: Option<u64>,
+        /// Only jobs created less than this many days ago
+        #[arg(long, value_name = "DAYS")]
+        newer_than: Option<u64>,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show a job by directory name or ULID
+    Show {
+        id: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove a job by directory name or ULID
+    Rm {
+        id: String,
+        /// Also remove a job that is still queued or running
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StatusFilter {
+    Active,
+    Finished,
+    Failed,
+}
+
+impl StatusFilter {
+    fn matches(&self, job: &Job) -> bool {
+        match self {
+            StatusFilter::Active => !job.is_finished() && !job.is_failed(),
+            StatusFilter::Finished => job.is_finished(),
+            StatusFilter::Failed => job.is_failed(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JobSummary {
+    id: String,
+    state: JobState,
+    /// Seconds since the Unix epoch, from the ULID of the directory name
+    created_at: Option<u64>,
+    size: u64,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JobDetails {
+    #[serde(flatten)]
+    summary: JobSummary,
+    path: String,
+    completed: JobState,
+    chat_id: Option<i64>,
+    preset: Option<String>,
+    options: Option<Options>,
+    durations: BTreeMap<JobState, f64>,
+    inputs: Vec<String>,
+    dicom_series: Vec<Series>,
+    series: Vec<String>,
+    /// Stacks picked for the reconstruction, every one when unset
+    selected_stacks: Option<Vec<String>>,
+    masks: Vec<String>,
+    output_nifti: Option<String>,
+    output: Option<String>,
+}
+
+fn job_summary(working_directory: &WorkingDirectory, job: &Job) -> JobSummary {
+    JobSummary {
+        id: working_directory.directory.clone(),
+        state: job.state,
+        created_at: working_directory
+            .files
+            .created_at()
+            .and_then(|created_at| created_at.duration_since(UNIX_EPOCH).ok())
+            .map(|created_at| created_at.as_secs()),
+        size: working_directory.files.size(),
+        error: job.error.clone(),
+    }
+}
+
+fn job_details(working_directory: &WorkingDirectory) -> Result<JobDetails> {
+    let job = working_directory.load_job()?;
+    let files = &working_directory.files;
+    let output_nifti = files.output_nifti();
+    Ok(JobDetails {
+        summary: job_summary(working_directory, &job),
+        path: working_directory.path.display().to_string(),
+        completed: job.completed,
+        chat_id: job.chat_id,
+        preset: job.preset.clone(),
+        options: job.options.clone(),
+        durations: job.durations.clone(),
+        inputs: files.archive().list_files(),
+        dicom_series: job.series.clone(),
+        series: files.nii().nifti_files(),
+        selected_stacks: job.stacks.clone(),
+        masks: files.masks().files_with_extension("gz"),
+        output_nifti: output_nifti
+            .is_file()
+            .then(|| output_nifti.display().to_string()),
+        output: job.output.clone(),
+    })
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC.
+fn format_timestamp(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let minutes = seconds % 86400 / 60;
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn list_jobs(
+    config: &Config,
+    status: Option<StatusFilter>,
+    older_than: Option<u64>,
+    newer_than: Option<u64>,
+    json: bool,
+) -> Result<()> {
+    let now = SystemTime::now();
+    let age_days = |working_directory: &WorkingDirectory| {
+        working_directory
+            .files
+            .created_at()
+            .and_then(|created_at| now.duration_since(created_at).ok())
+            .map(|age| age.as_secs() / 86400)
+    };
+    let mut summaries = Vec::new();
+    for working_directory in WorkingDirectory::list(&config.output.base_directory)? {
+        let job = match working_directory.load_job() {
+            Ok(job) => job,
+            Err(error) => {
+                log::warn!("Skipping {}: {}", working_directory.directory, error);
+                continue;
+            }
+        };
+        let age = age_days(&working_directory);
+        if status.is_some_and(|status| !status.matches(&job))
+            || older_than.is_some_and(|days| age.map_or(true, |age| age < days))
+            || newer_than.is_some_and(|days| age.map_or(true, |age| age >= days))
+        {
+            continue;
+        }
+        summaries.push(job_summary(&working_directory, &job));
+    }
+    if json {
+        return print_json(&summaries);
+    }
+    let rows: Vec<[String; 4]> = summaries
+        .iter()
+        .map(|summary| {
+            [
+                summary.id.clone(),
+                format!("{:?}", summary.state),
+                summary.created_at.map(format_timestamp).unwrap_or_default(),
+                format_size(summary.size),
+            ]
+        })
+        .collect();
+    let header = ["ID", "STATE", "CREATED", "SIZE"].map(str::to_string);
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in std::iter::once(&header).chain(&rows) {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+    }
+    Ok(())
+}
+
+fn show_job(config: &Config, id: &str, json: bool) -> Result<()> {
+    let working_directory = WorkingDirectory::find(&config.output.base_directory, id)?;
+    let details = job_details(&working_directory)?;
+    if json {
+        return print_json(&details);
+    }
+    let summary = &details.summary;
+    println!("ID:        {}", summary.id);
+    println!("Path:      {}", details.path);
+    println!("State:     {:?}", summary.state);
+    println!("Completed: {:?}", details.completed);
+    if let Some(created_at) = summary.created_at {
+        println!("Created:   {}", format_timestamp(created_at));
+    }
+    println!("Size:      {}", format_size(summary.size));
+    if let Some(error) = &summary.error {
+        println!("Error:     {}", error);
+    }
+    if let Some(preset) = &details.preset {
+        println!("Preset:    {}", preset);
+    }
+    if let Some(options) = &details.options {
+        println!("Options:   {}", options.to_args().join(" "));
+    }
+    for (step, seconds) in &details.durations {
+        println!(
+            "Duration:  {:?} {}",
+            step,
+            humanize(Duration::from_secs_f64(*seconds))
+        );
+    }
+    for series in &details.dicom_series {
+        let mark = if series.selected { "*" } else { " " };
+        println!("DICOM:   {} {}", mark, series);
+    }
+    for (label, files) in [
+        ("Inputs", &details.inputs),
+        ("Series", &details.series),
+        ("Masks", &details.masks),
+    ] {
+        println!("{}: {}", label, files.len());
+        for file in files {
+            println!("  {}", file);
+        }
+    }
+    if let Some(stacks) = &details.selected_stacks {
+        println!("Selected:  {}", stacks.join(", "));
+    }
+    if let Some(output_nifti) = &details.output_nifti {
+        println!("Volume:    {}", output_nifti);
+    }
+    if let Some(output) = &details.output {
+        println!("Output:    {}", output);
+    }
+    Ok(())
+}
+
+fn humanize(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}h{:02}m{:02}s",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+fn remove_job(config: &Config, id: &str, force: bool) -> Result<()> {
+    let working_directory = WorkingDirectory::find(&config.output.base_directory, id)?;
+    let job = working_directory.load_job()?;
+    if !force && StatusFilter::Active.matches(&job) {
+        return Err(Error::JobActive(working_directory.directory));
+    }
+    std::fs::remove_dir_all(&working_directory.path)?;
+    println!("Removed {}", working_directory.directory);
+    Ok(())
+}
+
+fn new_niftymic(
+    inputs: &[String],
+    config: &Config,
+    cancel: &CancellationToken,
+) -> Result<NiftyMic> {
+    let mut niftymic = NiftyMic::from_inputs(inputs, config)?;
+    niftymic.set_cancellation_token(cancel.clone());
+    Ok(niftymic)
+}
+
+fn open_niftymic(
+    working_directory: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> Result<NiftyMic> {
+    let mut niftymic = NiftyMic::from_working_directory(working_directory, config)?;
+    niftymic.set_cancellation_token(cancel.clone());
+    Ok(niftymic)
 }
 
 fn execute_cmdline() -> Result<()> {
     let cli = Cli::parse();
     let config = Config::new(cli.config)?;
+    let cancel = CancellationToken::new();
+    let handler_cancel = cancel.clone();
+    if let Err(error) = ctrlc::set_handler(move || {
+        log::warn!("Interrupted, stopping the current step");
+        handler_cancel.cancel();
+    }) {
+        log::warn!("Failed to install Ctrl-C handler: {}", error);
+    }
 
     match &cli.command {
-        Commands::ConvertDicom { archive_path } => {
-            let niftymic = NiftyMic::new(archive_path, &config)?;
-            niftymic.convert_dicom_to_nifti()
-        }
-        Commands::Pipeline { archive_path } => {
-            let niftymic = NiftyMic::new(archive_path, &config)?;
-            niftymic.convert_dicom_to_nifti()?;
-            niftymic.generate_masks_from_nifti()?;
-            niftymic.reconstruct(Options::default())?;
-            let result = niftymic.convert_nifti_to_dicom()?;
+        Commands::ConvertDicom { inputs } => {
+            let niftymic = new_niftymic(inputs, &config, &cancel)?;
+            niftymic.convert_dicom_to_nifti(&[])
+        }
+        Commands::Pipeline {
+            inputs,
+            preset,
+            options,
+        } => {
+            let base = match preset {
+                Some(preset) => preset::resolve(preset, &config)?,
+                None => config.reconstruction.clone(),
+            };
+            let options = options.apply(base)?;
+            let mut niftymic = new_niftymic(inputs, &config, &cancel)?;
+            niftymic.set_progress_callback(Arc::new(|progress| log::info!("{}", progress)));
+            niftymic
+                .working_directory()
+                .update_job(|job| job.preset = preset.clone())?;
+            let result = niftymic.run(&options)?;
+            log::info!("Result: {}", result);
+            Ok(())
+        }
+        Commands::Resume {
+            working_directory,
+            options,
+        } => {
+            let mut niftymic = open_niftymic(working_directory, &config, &cancel)?;
+            niftymic.set_progress_callback(Arc::new(|progress| log::info!("{}", progress)));
+            let job = niftymic.working_directory().load_job()?;
+            let options = options.apply(job.options.unwrap_or(config.reconstruction.clone()))?;
+            let result = niftymic.run(&options)?;
             log::info!("Result: {}", result);
             Ok(())
         }
         Commands::GenerateMasks { working_directory } => {
-            let niftymic = NiftyMic::from_working_directory(working_directory, &config)?;
+            let niftymic = open_niftymic(working_directory, &config, &cancel)?;
             niftymic.generate_masks_from_nifti()
         }
-        Commands::Reconstruct { working_directory } => {
-            let niftymic = NiftyMic::from_working_directory(working_directory, &config)?;
-            let options = Options::default();
-            niftymic.reconstruct(options)
+        Commands::Reconstruct {
+            working_directory,
+            options,
+        } => {
+            let options = options.apply(config.reconstruction.clone())?;
+            let niftymic = open_niftymic(working_directory, &config, &cancel)?;
+            niftymic.reconstruct(&options)
         }
         Commands::ConvertNifti { working_directory } => {
-            let niftymic = NiftyMic::from_working_directory(working_directory, &config)?;
-            let result = niftymic.convert_nifti_to_dicom()?;
+            let niftymic = open_niftymic(working_directory, &config, &cancel)?;
+            let job = niftymic.working_directory().load_job()?;
+            let result = niftymic.convert_nifti_to_dicom(job.study.as_ref())?;
             log::info!("Result: {}", result);
             Ok(())
         }
+        Commands::Jobs { command } => match command {
+            JobsCommand::List {
+                status,
+                older_than,
+                newer_than,
+                json,
+            } => list_jobs(&config, *status, *older_than, *newer_than, *json),
+            JobsCommand::Show { id, json } => show_job(&config, id, *json),
+            JobsCommand::Rm { id, force } => remove_job(&config, id, *force),
+        },
+        Commands::Gc { dry_run } => {
+            let removals =
+                retention::collect(&config.output.base_directory, &config.retention, *dry_run)?;
+            for removal in &removals {
+                println!(
+                    "{}\t{}\t{} MB",
+                    removal.entry.path.display(),
+                    removal.reason,
+                    removal.entry.size / (1024 * 1024)
+                );
+            }
+            let freed: u64 = removals.iter().map(|removal| removal.entry.size).sum();
+            log::info!(
+                "{} {} working directories, {} MB",
+                if *dry_run { "Would remove" } else { "Removed" },
+                removals.len(),
+                freed / (1024 * 1024)
+            );
+            Ok(())
+        }
     }
 }
 
 fn main() {
     pretty_env_logger::init();
     match execute_cmdline() {
-        Ok(_) => println!("Terminated with no errors"),
+        Ok(_) => log::info!("Terminated with no errors"),
+        Err(Error::CommandFailed(failure)) => error!("{}", failure.report()),
         Err(err) => error!("{}", err.to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1709210040), "2024-02-29 12:34");
+        assert_eq!(format_timestamp(1709251200), "2024-03-01 00:00");
+        assert_eq!(format_timestamp(1704067140), "2023-12-31 23:59");
+        assert_eq!(format_timestamp(1704067200), "2024-01-01 00:00");
+    }
+}
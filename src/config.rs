@@ -1,26 +1,103 @@
 use config::{Config as ConfigRs, ConfigError, Environment, File};
 use log::debug;
 use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::archive::{self, ExtractLimits};
+use crate::inventory::{self, Orientation};
+use crate::niftymic::Options;
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/niftymic/niftymic.toml";
 const ENV_PREFIX: &str = "NIFTYMIC";
 
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    #[default]
+    Docker,
+    Podman,
+    Apptainer,
+    /// NiftyMIC installed on the host, no container involved.
+    Native,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Docker {
     pub image: String,
     pub working_directory: String,
+    #[serde(default)]
+    pub runtime: Runtime,
+}
+
+/// Resource limits and isolation applied to every container run.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ContainerSettings {
+    /// Number of CPUs, e.g. `2.5`.
+    pub cpus: Option<f32>,
+    /// Memory limit, e.g. `16g`.
+    pub memory: Option<String>,
+    /// `uid[:gid]` to run as, or `owner` for the owner of the job directory.
+    pub user: Option<String>,
+    pub read_only: bool,
+    pub disable_network: bool,
+    /// Additional `host:container[:options]` mounts.
+    pub mounts: Vec<String>,
+    pub env: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Executable {
     pub dcm2niix: String,
     pub docker: String,
+    #[serde(default = "default_medcon")]
     pub medcon: String,
+    #[serde(default = "default_podman")]
+    pub podman: String,
+    #[serde(default = "default_apptainer")]
+    pub apptainer: String,
+    /// Directory of the NiftyMIC executables for the native runtime.
+    pub niftymic_bin_directory: Option<String>,
+}
+
+fn default_medcon() -> String {
+    "medcon".to_string()
+}
+
+fn default_podman() -> String {
+    "podman".to_string()
+}
+
+fn default_apptainer() -> String {
+    "apptainer".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Output {
     pub base_directory: String,
+    /// Deflate level of the output archive, 0 (stored) to 9.
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+}
+
+/// Writer of the output DICOM series.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportBackend {
+    /// In-crate writer, falling back to medcon when it fails.
+    #[default]
+    Native,
+    Medcon,
+}
+
+/// Conversion of the reconstruction to DICOM.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Export {
+    pub backend: ExportBackend,
+    /// Write a single enhanced MR multi-frame image instead of one file per
+    /// slice, native backend only.
+    pub multiframe: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,6 +105,65 @@ pub struct Telegram {
     pub enable: bool,
     pub teloxide_token: String,
     pub channel_id: String,
+    /// Number of reconstructions allowed to run at the same time.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+/// Per-step time limits in seconds, no limit when unset.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Timeouts {
+    pub convert: Option<u64>,
+    pub masks: Option<u64>,
+    pub reconstruct: Option<u64>,
+    pub export: Option<u64>,
+}
+
+/// Cleanup of old working directories, nothing is removed when unset.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Retention {
+    /// Age after which finished jobs are removed.
+    pub max_age_days: Option<u64>,
+    /// Age after which failed or incomplete jobs are removed, `max_age_days` when unset.
+    pub keep_failed_days: Option<u64>,
+    /// Age after which queued, running or paused jobs are considered abandoned
+    /// and removed, never when unset.
+    pub keep_active_days: Option<u64>,
+    /// Total size of the base directory above which the oldest jobs are removed.
+    pub max_total_size_mb: Option<u64>,
+    /// Period of the cleaner running inside the bot, disabled when unset.
+    pub interval_minutes: Option<u64>,
+}
+
+/// Rules picking the DICOM series to reconstruct, every series passes when unset.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Selection {
+    /// Regex matched against the series description, e.g. `(?i)haste|ssfse`.
+    pub description: Option<String>,
+    /// Regex matched against the scanning sequence and the sequence name.
+    pub sequence: Option<String>,
+    pub min_slices: Option<usize>,
+    /// Accepted acquisition planes, any when empty.
+    pub orientations: Vec<Orientation>,
+}
+
+/// De-identification of the DICOM inputs and outputs, based on the PS3.15
+/// basic profile. Disabled by default.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Deidentification {
+    pub enable: bool,
+    /// Attributes kept despite the profile, by keyword, e.g. `PatientAge`.
+    pub keep: Vec<String>,
+    /// Attributes removed in addition to the profile, by keyword.
+    pub remove: Vec<String>,
+    pub keep_private_tags: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -35,7 +171,26 @@ pub struct Config {
     pub output: Output,
     pub executables: Executable,
     pub docker: Docker,
+    #[serde(default)]
+    pub container: ContainerSettings,
     pub telegram: Option<Telegram>,
+    #[serde(default)]
+    pub reconstruction: Options,
+    #[serde(default)]
+    pub presets: BTreeMap<String, Options>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub retention: Retention,
+    /// Limits applied when extracting uploaded archives.
+    #[serde(default)]
+    pub archive: ExtractLimits,
+    #[serde(default)]
+    pub selection: Selection,
+    #[serde(default)]
+    pub deidentification: Deidentification,
+    #[serde(default)]
+    pub export: Export,
 }
 
 impl Config {
@@ -56,6 +211,17 @@ impl Config {
             )
             .build()?;
         debug!("Try deserializing configuration");
-        result.try_deserialize()
+        let config: Config = result.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings which would only fail once a job reaches them.
+    fn validate(&self) -> Result<(), ConfigError> {
+        inventory::validate(&self.selection)
+            .map_err(|error| ConfigError::Message(error.to_string()))?;
+        archive::validate_compression_level(self.output.compression_level)
+            .map_err(|error| ConfigError::Message(error.to_string()))?;
+        Ok(())
     }
 }
@@ -0,0 +1,470 @@
+use log::{info, warn};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use ulid::Ulid;
+
+use crate::config::{Config, ContainerSettings, Runtime};
+use crate::niftymic::{Error, Result};
+use crate::spawn::{spawn_command, Supervision};
+
+/// Backend able to run the NiftyMIC tools against a job working directory.
+pub trait ContainerRuntime: Send + Sync {
+    /// Path where the job working directory is mounted inside the container,
+    /// `None` when the tools see the host paths.
+    fn mount_point(&self) -> Option<&str>;
+
+    /// Program and arguments running `command` in the container named `name`.
+    fn command_line(
+        &self,
+        command: &str,
+        args: &[String],
+        working_directory: &str,
+        name: &str,
+    ) -> (String, Vec<String>);
+
+    /// Stops the container `name` once its client process was killed.
+    fn kill(&self, _name: &str) {}
+
+    /// Runs `command` in a new container, stopping it if the run is interrupted.
+    fn run(
+        &self,
+        command: &str,
+        args: &[String],
+        working_directory: &str,
+        supervision: &Supervision,
+        on_line: &dyn Fn(&str),
+    ) -> Result<()> {
+        let name = format!("niftymic-{}", Ulid::new().to_string().to_lowercase());
+        let (program, command_line) = self.command_line(command, args, working_directory, &name);
+        let result = spawn_command(
+            &program,
+            &command_line,
+            Some(working_directory),
+            supervision,
+            on_line,
+        );
+        if let Err(Error::Cancelled | Error::TimedOut(_, _)) = result {
+            self.kill(&name);
+        }
+        result
+    }
+}
+
+pub fn from_config(config: &Config) -> Box<dyn ContainerRuntime> {
+    match config.docker.runtime {
+        Runtime::Docker => Box::new(DockerWrapper::from_config(config)),
+        Runtime::Podman => Box::new(PodmanWrapper::from_config(config)),
+        Runtime::Apptainer => Box::new(ApptainerWrapper::from_config(config)),
+        Runtime::Native => Box::new(NativeRunner::from_config(config)),
+    }
+}
+
+fn container_command_line(
+    executable: &str,
+    mut run_args: Vec<String>,
+    command: &str,
+    args: &[String],
+) -> (String, Vec<String>) {
+    run_args.push(command.to_string());
+    run_args.extend_from_slice(args);
+    (executable.to_string(), run_args)
+}
+
+fn kill_container(executable: &str, name: &str) {
+    info!("Killing container {}", name);
+    let killed = Command::new(executable)
+        .args(["kill", name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if !killed.is_ok_and(|status| status.success()) {
+        warn!("Failed to kill container {}", name);
+    }
+}
+
+/// Resolves the configured user, `owner` mapping to the owner of `working_directory`.
+fn container_user(user: &str, working_directory: &str) -> String {
+    if user != "owner" {
+        return user.to_string();
+    }
+    match std::fs::metadata(working_directory) {
+        Ok(metadata) => format!("{}:{}", metadata.uid(), metadata.gid()),
+        Err(error) => {
+            warn!(
+                "Failed to read owner of {}, running as image user: {}",
+                working_directory, error
+            );
+            String::new()
+        }
+    }
+}
+
+/// Limits and isolation flags shared by Docker and Podman.
+fn isolation_args(settings: &ContainerSettings, working_directory: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(cpus) = settings.cpus {
+        args.push(format!("--cpus={}", cpus));
+    }
+    if let Some(memory) = &settings.memory {
+        args.push(format!("--memory={}", memory));
+    }
+    if let Some(user) = &settings.user {
+        let user = container_user(user, working_directory);
+        if !user.is_empty() {
+            args.push(format!("--user={}", user));
+        }
+    }
+    if settings.read_only {
+        args.push("--read-only".to_string());
+        args.push("--tmpfs=/tmp".to_string());
+    }
+    if settings.disable_network {
+        args.push("--network=none".to_string());
+    }
+    for mount in &settings.mounts {
+        args.push("-v".to_string());
+        args.push(mount.to_string());
+    }
+    for (key, value) in &settings.env {
+        args.push("-e".to_string());
+        args.push(format!("{}={}", key, value));
+    }
+    args
+}
+
+pub struct DockerWrapper {
+    pub executable: String,
+    pub working_directory: String,
+    pub image: String,
+    pub settings: ContainerSettings,
+}
+
+impl DockerWrapper {
+    pub fn new(executable: &str, working_directory: &str, image: &str) -> DockerWrapper {
+        DockerWrapper {
+            executable: executable.to_string(),
+            working_directory: working_directory.to_string(),
+            image: image.to_string(),
+            settings: ContainerSettings::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> DockerWrapper {
+        DockerWrapper {
+            settings: config.container.clone(),
+            ..DockerWrapper::new(
+                &config.executables.docker,
+                &config.docker.working_directory,
+                &config.docker.image,
+            )
+        }
+    }
+
+    pub fn get_run_command_args(&self, working_directory: &str, name: &str) -> Vec<String> {
+        let mut args = vec![
+            "run".to_string(),
+            "--rm".to_string(),
+            "--name".to_string(),
+            name.to_string(),
+        ];
+        args.append(&mut isolation_args(&self.settings, working_directory));
+        args.push("-v".to_string());
+        args.push(format!("{}:{}", working_directory, self.working_directory));
+        args.push(self.image.to_string());
+        args
+    }
+}
+
+impl ContainerRuntime for DockerWrapper {
+    fn mount_point(&self) -> Option<&str> {
+        Some(&self.working_directory)
+    }
+
+    fn command_line(
+        &self,
+        command: &str,
+        args: &[String],
+        working_directory: &str,
+        name: &str,
+    ) -> (String, Vec<String>) {
+        let run_args = self.get_run_command_args(working_directory, name);
+        container_command_line(&self.executable, run_args, command, args)
+    }
+
+    fn kill(&self, name: &str) {
+        kill_container(&self.executable, name);
+    }
+}
+
+/// Rootless Podman, keeping the host user id so outputs stay owned by the caller.
+pub struct PodmanWrapper {
+    pub executable: String,
+    pub working_directory: String,
+    pub image: String,
+    pub settings: ContainerSettings,
+}
+
+impl PodmanWrapper {
+    pub fn new(executable: &str, working_directory: &str, image: &str) -> PodmanWrapper {
+        PodmanWrapper {
+            executable: executable.to_string(),
+            working_directory: working_directory.to_string(),
+            image: image.to_string(),
+            settings: ContainerSettings::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> PodmanWrapper {
+        PodmanWrapper {
+            settings: config.container.clone(),
+            ..PodmanWrapper::new(
+                &config.executables.podman,
+                &config.docker.working_directory,
+                &config.docker.image,
+            )
+        }
+    }
+
+    pub fn get_run_command_args(&self, working_directory: &str, name: &str) -> Vec<String> {
+        let mut args = vec![
+            "run".to_string(),
+            "--rm".to_string(),
+            "--name".to_string(),
+            name.to_string(),
+            "--userns=keep-id".to_string(),
+        ];
+        args.append(&mut isolation_args(&self.settings, working_directory));
+        args.push("-v".to_string());
+        args.push(format!(
+            "{}:{}:Z",
+            working_directory, self.working_directory
+        ));
+        args.push(self.image.to_string());
+        args
+    }
+}
+
+impl ContainerRuntime for PodmanWrapper {
+    fn mount_point(&self) -> Option<&str> {
+        Some(&self.working_directory)
+    }
+
+    fn command_line(
+        &self,
+        command: &str,
+        args: &[String],
+        working_directory: &str,
+        name: &str,
+    ) -> (String, Vec<String>) {
+        let run_args = self.get_run_command_args(working_directory, name);
+        container_command_line(&self.executable, run_args, command, args)
+    }
+
+    fn kill(&self, name: &str) {
+        kill_container(&self.executable, name);
+    }
+}
+
+/// Apptainer/Singularity, running the tools with `exec` against a SIF file or `docker://` image.
+/// The contained process dies with its client, so no explicit kill is needed.
+pub struct ApptainerWrapper {
+    pub executable: String,
+    pub working_directory: String,
+    pub image: String,
+    pub settings: ContainerSettings,
+}
+
+impl ApptainerWrapper {
+    pub fn new(executable: &str, working_directory: &str, image: &str) -> ApptainerWrapper {
+        ApptainerWrapper {
+            executable: executable.to_string(),
+            working_directory: working_directory.to_string(),
+            image: image.to_string(),
+            settings: ContainerSettings::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> ApptainerWrapper {
+        ApptainerWrapper {
+            settings: config.container.clone(),
+            ..ApptainerWrapper::new(
+                &config.executables.apptainer,
+                &config.docker.working_directory,
+                &config.docker.image,
+            )
+        }
+    }
+
+    pub fn get_run_command_args(&self, working_directory: &str, _name: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        args.push("exec".to_string());
+        args.push("--cleanenv".to_string());
+        // Apptainer always runs as the calling user and keeps the image read-only.
+        if let Some(cpus) = self.settings.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+        if let Some(memory) = &self.settings.memory {
+            args.push(format!("--memory={}", memory));
+        }
+        if self.settings.disable_network {
+            args.push("--net".to_string());
+            args.push("--network=none".to_string());
+        }
+        for mount in &self.settings.mounts {
+            args.push("--bind".to_string());
+            args.push(mount.to_string());
+        }
+        for (key, value) in &self.settings.env {
+            args.push("--env".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        args.push("--bind".to_string());
+        args.push(format!("{}:{}", working_directory, self.working_directory));
+        args.push("--pwd".to_string());
+        args.push(self.working_directory.to_string());
+        args.push(self.image.to_string());
+        args
+    }
+}
+
+impl ContainerRuntime for ApptainerWrapper {
+    fn mount_point(&self) -> Option<&str> {
+        Some(&self.working_directory)
+    }
+
+    fn command_line(
+        &self,
+        command: &str,
+        args: &[String],
+        working_directory: &str,
+        name: &str,
+    ) -> (String, Vec<String>) {
+        let run_args = self.get_run_command_args(working_directory, name);
+        container_command_line(&self.executable, run_args, command, args)
+    }
+}
+
+/// Runs the tools installed on the host, e.g. in a Python virtualenv, with host paths.
+pub struct NativeRunner {
+    /// Directory holding the NiftyMIC executables, looked up in `PATH` when unset.
+    pub bin_directory: Option<String>,
+}
+
+impl NativeRunner {
+    pub fn new(bin_directory: Option<&str>) -> NativeRunner {
+        NativeRunner {
+            bin_directory: bin_directory.map(str::to_string),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> NativeRunner {
+        NativeRunner::new(config.executables.niftymic_bin_directory.as_deref())
+    }
+}
+
+impl ContainerRuntime for NativeRunner {
+    fn mount_point(&self) -> Option<&str> {
+        None
+    }
+
+    fn command_line(
+        &self,
+        command: &str,
+        args: &[String],
+        _working_directory: &str,
+        _name: &str,
+    ) -> (String, Vec<String>) {
+        let program = match &self.bin_directory {
+            Some(bin_directory) => Path::new(bin_directory).join(command).display().to_string(),
+            None => command.to_string(),
+        };
+        (program, args.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_run_args() {
+        let docker = DockerWrapper::new("docker", "/app/data", "renbem/niftymic");
+        assert_eq!(
+            docker
+                .get_run_command_args("/srv/job", "niftymic-1")
+                .join(" "),
+            "run --rm --name niftymic-1 -v /srv/job:/app/data renbem/niftymic"
+        );
+    }
+
+    #[test]
+    fn test_podman_run_args() {
+        let podman = PodmanWrapper::new("podman", "/app/data", "renbem/niftymic");
+        assert_eq!(
+            podman
+                .get_run_command_args("/srv/job", "niftymic-1")
+                .join(" "),
+            "run --rm --name niftymic-1 --userns=keep-id -v /srv/job:/app/data:Z renbem/niftymic"
+        );
+    }
+
+    #[test]
+    fn test_docker_isolation_args() {
+        let mut docker = DockerWrapper::new("docker", "/app/data", "renbem/niftymic");
+        docker.settings = ContainerSettings {
+            cpus: Some(4.0),
+            memory: Some("16g".to_string()),
+            user: Some("1000:1000".to_string()),
+            read_only: true,
+            disable_network: true,
+            mounts: vec!["/opt/models:/models:ro".to_string()],
+            env: [("OMP_NUM_THREADS".to_string(), "4".to_string())].into(),
+        };
+        assert_eq!(
+            docker
+                .get_run_command_args("/srv/job", "niftymic-1")
+                .join(" "),
+            "run --rm --name niftymic-1 --cpus=4 --memory=16g --user=1000:1000 --read-only \
+             --tmpfs=/tmp --network=none -v /opt/models:/models:ro -e OMP_NUM_THREADS=4 \
+             -v /srv/job:/app/data renbem/niftymic"
+        );
+    }
+
+    #[test]
+    fn test_owner_user_mapping() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().display().to_string();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(
+            container_user("owner", &path),
+            format!("{}:{}", metadata.uid(), metadata.gid())
+        );
+        assert_eq!(container_user("1000", &path), "1000");
+    }
+
+    #[test]
+    fn test_native_command_line() {
+        let native = NativeRunner::new(Some("/opt/niftymic/bin"));
+        let (program, args) = native.command_line(
+            "niftymic_reconstruct_volume",
+            &["--alpha".to_string(), "0.01".to_string()],
+            "/srv/job",
+            "niftymic-1",
+        );
+        assert_eq!(program, "/opt/niftymic/bin/niftymic_reconstruct_volume");
+        assert_eq!(args, vec!["--alpha", "0.01"]);
+        assert_eq!(native.mount_point(), None);
+    }
+
+    #[test]
+    fn test_apptainer_run_args() {
+        let apptainer = ApptainerWrapper::new("apptainer", "/app/data", "/opt/niftymic.sif");
+        assert_eq!(
+            apptainer
+                .get_run_command_args("/srv/job", "niftymic-1")
+                .join(" "),
+            "exec --cleanenv --bind /srv/job:/app/data --pwd /app/data /opt/niftymic.sif"
+        );
+    }
+}
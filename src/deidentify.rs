@@ -0,0 +1,512 @@
+use dicom_core::{dictionary::DataDictionary, DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::{tags, StandardDataDictionary};
+use dicom_object::{InMemDicomObject, OpenFileOptions};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    config::Deidentification,
+    dicom::{new_uid, UUID_ROOT},
+    dicomdir::DICOMDIR,
+    niftymic::{Error, Result},
+};
+
+/// Written to DeidentificationMethod (0012,0063). The profile is not claimed in
+/// full, `BASIC_PROFILE` lists what is covered.
+const METHOD: &str = "Subset of PS3.15 Basic Profile, SeriesDescription kept";
+
+/// What happens to an attribute, named after the action codes of PS3.15 Table E.1-1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// `X`: removed.
+    Remove,
+    /// `Z`: replaced by an empty value.
+    Empty,
+    /// `D`: replaced by the patient pseudonym.
+    Pseudonym,
+    /// `U`: replaced by a UID consistent within the job.
+    Uid,
+}
+
+/// Attributes of the basic profile found in MR studies, from PS3.15 Table E.1-1.
+/// The series description is kept, the stack selection relies on it; add it to
+/// `remove` to strip it. Unlisted sequences are searched recursively.
+const BASIC_PROFILE: &[(Tag, Action)] = &[
+    (tags::ACCESSION_NUMBER, Action::Empty),
+    (tags::ACQUISITION_DATE, Action::Remove),
+    (tags::ACQUISITION_DATE_TIME, Action::Remove),
+    (tags::ACQUISITION_TIME, Action::Remove),
+    (tags::ADDITIONAL_PATIENT_HISTORY, Action::Remove),
+    (tags::ADMISSION_ID, Action::Remove),
+    (tags::ADMITTING_DIAGNOSES_DESCRIPTION, Action::Remove),
+    (tags::ALLERGIES, Action::Remove),
+    (tags::BRANCH_OF_SERVICE, Action::Remove),
+    (tags::CONSULTING_PHYSICIAN_NAME, Action::Remove),
+    (tags::CONTENT_DATE, Action::Empty),
+    (tags::CONTENT_SEQUENCE, Action::Remove),
+    (tags::CONTENT_TIME, Action::Empty),
+    (tags::CONTRIBUTION_DESCRIPTION, Action::Remove),
+    (tags::COUNTRY_OF_RESIDENCE, Action::Remove),
+    (tags::CURRENT_PATIENT_LOCATION, Action::Remove),
+    (tags::DERIVATION_DESCRIPTION, Action::Remove),
+    (tags::DEVICE_SERIAL_NUMBER, Action::Remove),
+    (tags::DEVICE_UID, Action::Uid),
+    (tags::ETHNIC_GROUP, Action::Remove),
+    (
+        tags::FILLER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST,
+        Action::Remove,
+    ),
+    (tags::FRAME_OF_REFERENCE_UID, Action::Uid),
+    (tags::IMAGE_COMMENTS, Action::Remove),
+    (tags::IMAGING_SERVICE_REQUEST_COMMENTS, Action::Remove),
+    (tags::INSTANCE_CREATION_DATE, Action::Remove),
+    (tags::INSTANCE_CREATION_TIME, Action::Remove),
+    (tags::INSTANCE_CREATOR_UID, Action::Uid),
+    (tags::INSTITUTIONAL_DEPARTMENT_NAME, Action::Remove),
+    (tags::INSTITUTION_ADDRESS, Action::Remove),
+    (tags::INSTITUTION_CODE_SEQUENCE, Action::Remove),
+    (tags::INSTITUTION_NAME, Action::Remove),
+    (tags::IRRADIATION_EVENT_UID, Action::Uid),
+    (tags::ISSUER_OF_PATIENT_ID, Action::Remove),
+    (
+        tags::ISSUER_OF_PATIENT_ID_QUALIFIERS_SEQUENCE,
+        Action::Remove,
+    ),
+    (tags::LAST_MENSTRUAL_DATE, Action::Remove),
+    (tags::MEDICAL_ALERTS, Action::Remove),
+    (Tag(0x0010, 0x1090), Action::Remove), // MedicalRecordLocator, retired
+    (tags::MILITARY_RANK, Action::Remove),
+    (tags::MODIFIED_ATTRIBUTES_SEQUENCE, Action::Remove),
+    (tags::NAME_OF_PHYSICIANS_READING_STUDY, Action::Remove),
+    (tags::OCCUPATION, Action::Remove),
+    (tags::OPERATORS_NAME, Action::Remove),
+    (tags::OPERATOR_IDENTIFICATION_SEQUENCE, Action::Remove),
+    (tags::ORDER_CALLBACK_PHONE_NUMBER, Action::Remove),
+    (tags::ORDER_ENTERED_BY, Action::Remove),
+    (tags::ORDER_ENTERER_LOCATION, Action::Remove),
+    (tags::ORIGINAL_ATTRIBUTES_SEQUENCE, Action::Remove),
+    (Tag(0x0010, 0x1000), Action::Remove), // OtherPatientIDs, retired
+    (tags::OTHER_PATIENT_I_DS_SEQUENCE, Action::Remove),
+    (tags::OTHER_PATIENT_NAMES, Action::Remove),
+    (tags::PATIENT_ADDRESS, Action::Remove),
+    (tags::PATIENT_AGE, Action::Remove),
+    (tags::PATIENT_ALTERNATIVE_CALENDAR, Action::Remove),
+    (tags::PATIENT_BIRTH_DATE, Action::Empty),
+    (
+        tags::PATIENT_BIRTH_DATE_IN_ALTERNATIVE_CALENDAR,
+        Action::Remove,
+    ),
+    (tags::PATIENT_BIRTH_NAME, Action::Remove),
+    (tags::PATIENT_BIRTH_TIME, Action::Remove),
+    (tags::PATIENT_COMMENTS, Action::Remove),
+    (
+        tags::PATIENT_DEATH_DATE_IN_ALTERNATIVE_CALENDAR,
+        Action::Remove,
+    ),
+    (tags::PATIENT_ID, Action::Pseudonym),
+    (tags::PATIENT_INSTITUTION_RESIDENCE, Action::Remove),
+    (tags::PATIENT_INSURANCE_PLAN_CODE_SEQUENCE, Action::Remove),
+    (tags::PATIENT_MOTHER_BIRTH_NAME, Action::Remove),
+    (tags::PATIENT_NAME, Action::Pseudonym),
+    (tags::PATIENT_PRIMARY_LANGUAGE_CODE_SEQUENCE, Action::Remove),
+    (tags::PATIENT_RELIGIOUS_PREFERENCE, Action::Remove),
+    (tags::PATIENT_SEX, Action::Empty),
+    (tags::PATIENT_SIZE, Action::Remove),
+    (tags::PATIENT_STATE, Action::Remove),
+    (tags::PATIENT_TELEPHONE_NUMBERS, Action::Remove),
+    (tags::PATIENT_TRANSPORT_ARRANGEMENTS, Action::Remove),
+    (tags::PATIENT_WEIGHT, Action::Remove),
+    (tags::PERFORMED_LOCATION, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_END_DATE, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_END_TIME, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_ID, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_START_DATE, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_START_TIME, Action::Remove),
+    (tags::PERFORMED_STATION_NAME, Action::Remove),
+    (
+        tags::PERFORMING_PHYSICIAN_IDENTIFICATION_SEQUENCE,
+        Action::Remove,
+    ),
+    (tags::PERFORMING_PHYSICIAN_NAME, Action::Remove),
+    (tags::PERSON_NAME, Action::Remove),
+    (tags::PHYSICIANS_OF_RECORD, Action::Remove),
+    (
+        tags::PHYSICIANS_OF_RECORD_IDENTIFICATION_SEQUENCE,
+        Action::Remove,
+    ),
+    (
+        tags::PHYSICIANS_READING_STUDY_IDENTIFICATION_SEQUENCE,
+        Action::Remove,
+    ),
+    (
+        tags::PLACER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST,
+        Action::Remove,
+    ),
+    (tags::PREGNANCY_STATUS, Action::Remove),
+    (tags::PROTOCOL_NAME, Action::Remove),
+    (tags::REASON_FOR_THE_REQUESTED_PROCEDURE, Action::Remove),
+    (tags::REFERENCED_PATIENT_SEQUENCE, Action::Remove),
+    (
+        tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE,
+        Action::Remove,
+    ),
+    (tags::REFERENCED_SOP_INSTANCE_UID, Action::Uid),
+    (tags::REFERENCED_STUDY_SEQUENCE, Action::Remove),
+    (tags::REFERRING_PHYSICIAN_ADDRESS, Action::Remove),
+    (
+        tags::REFERRING_PHYSICIAN_IDENTIFICATION_SEQUENCE,
+        Action::Remove,
+    ),
+    (tags::REFERRING_PHYSICIAN_NAME, Action::Empty),
+    (tags::REFERRING_PHYSICIAN_TELEPHONE_NUMBERS, Action::Remove),
+    (tags::REGION_OF_RESIDENCE, Action::Remove),
+    (tags::REQUESTED_PROCEDURE_COMMENTS, Action::Remove),
+    (tags::REQUESTED_PROCEDURE_DESCRIPTION, Action::Remove),
+    (tags::REQUESTED_PROCEDURE_ID, Action::Remove),
+    (tags::REQUESTING_PHYSICIAN, Action::Remove),
+    (tags::REQUESTING_SERVICE, Action::Remove),
+    (tags::REQUEST_ATTRIBUTES_SEQUENCE, Action::Remove),
+    (tags::RESPONSIBLE_ORGANIZATION, Action::Remove),
+    (tags::RESPONSIBLE_PERSON, Action::Remove),
+    (tags::SCHEDULED_PERFORMING_PHYSICIAN_NAME, Action::Remove),
+    (tags::SCHEDULED_PROCEDURE_STEP_DESCRIPTION, Action::Remove),
+    (tags::SCHEDULED_PROCEDURE_STEP_LOCATION, Action::Remove),
+    (tags::SERIES_DATE, Action::Remove),
+    (tags::SERIES_INSTANCE_UID, Action::Uid),
+    (tags::SERIES_TIME, Action::Remove),
+    (tags::SMOKING_STATUS, Action::Remove),
+    (tags::SOP_INSTANCE_UID, Action::Uid),
+    (tags::SPECIAL_NEEDS, Action::Remove),
+    (tags::STATION_NAME, Action::Remove),
+    (tags::STORAGE_MEDIA_FILE_SET_UID, Action::Uid),
+    (tags::STUDY_DATE, Action::Empty),
+    (tags::STUDY_DESCRIPTION, Action::Remove),
+    (tags::STUDY_ID, Action::Empty),
+    (tags::STUDY_INSTANCE_UID, Action::Uid),
+    (tags::STUDY_TIME, Action::Empty),
+    (tags::SYNCHRONIZATION_FRAME_OF_REFERENCE_UID, Action::Uid),
+    (tags::TEXT_VALUE, Action::Remove),
+    (tags::UID, Action::Uid),
+    (tags::VISIT_COMMENTS, Action::Remove),
+];
+
+/// Original values replaced in a job, kept next to it for re-identification.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    /// Original UID to the UID that replaced it.
+    pub uids: BTreeMap<String, String>,
+    /// Patient pseudonym to the original value of the attributes removed or
+    /// replaced, by keyword.
+    pub patients: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Mapping {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Mapping> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Ok(Mapping::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Applies the configured profile to the DICOM files of a job.
+pub struct Deidentifier {
+    actions: HashMap<Tag, Action>,
+    keep_private_tags: bool,
+    /// Prefix of the patient pseudonyms, e.g. the job ULID.
+    prefix: String,
+    mapping: Mapping,
+}
+
+fn parse_keyword(keyword: &str) -> Result<Tag> {
+    StandardDataDictionary
+        .parse_tag(keyword)
+        .ok_or_else(|| Error::InvalidDeidentification(format!("unknown attribute {}", keyword)))
+}
+
+fn keyword(tag: Tag) -> String {
+    StandardDataDictionary
+        .by_tag(tag)
+        .map(|entry| entry.alias.to_string())
+        .unwrap_or_else(|| tag.to_string())
+}
+
+fn text(element: &DataElement<InMemDicomObject>) -> String {
+    element
+        .to_str()
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .unwrap_or_default()
+}
+
+impl Deidentifier {
+    pub fn new(
+        settings: &Deidentification,
+        prefix: &str,
+        mapping: Mapping,
+    ) -> Result<Deidentifier> {
+        let mut actions: HashMap<Tag, Action> = BASIC_PROFILE.iter().copied().collect();
+        for keyword in &settings.keep {
+            actions.remove(&parse_keyword(keyword)?);
+        }
+        for keyword in &settings.remove {
+            actions.insert(parse_keyword(keyword)?, Action::Remove);
+        }
+        Ok(Deidentifier {
+            actions,
+            keep_private_tags: settings.keep_private_tags,
+            prefix: prefix.to_string(),
+            mapping,
+        })
+    }
+
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    fn uid(&mut self, original: &str) -> String {
+        // Already replaced, e.g. in outputs derived from de-identified inputs.
+        if original.starts_with(UUID_ROOT) && self.mapping.uids.values().any(|uid| uid == original)
+        {
+            return original.to_string();
+        }
+        self.mapping
+            .uids
+            .entry(original.to_string())
+            .or_insert_with(new_uid)
+            .clone()
+    }
+
+    /// Pseudonym of the patient identified by `patient_id`, created on first use.
+    fn pseudonym(&mut self, patient_id: &str) -> String {
+        if self.mapping.patients.contains_key(patient_id) {
+            return patient_id.to_string();
+        }
+        let existing = self.mapping.patients.iter().find(|(_, original)| {
+            original
+                .get("PatientID")
+                .map(String::as_str)
+                .unwrap_or_default()
+                == patient_id
+        });
+        if let Some((pseudonym, _)) = existing {
+            return pseudonym.clone();
+        }
+        let pseudonym = format!("{}-{}", self.prefix, self.mapping.patients.len() + 1);
+        let original = BTreeMap::from([("PatientID".to_string(), patient_id.to_string())]);
+        self.mapping.patients.insert(pseudonym.clone(), original);
+        pseudonym
+    }
+
+    /// Applies the profile to `object` and the items of its sequences, recording
+    /// the original values under `pseudonym`.
+    fn apply(&mut self, object: &mut InMemDicomObject, pseudonym: &str) {
+        let tags: Vec<Tag> = object.tags().collect();
+        for tag in tags {
+            let Some(element) = object.get(tag) else {
+                continue;
+            };
+            let vr = element.vr();
+            let original = text(element);
+            if tag.group() % 2 == 1 {
+                if !self.keep_private_tags {
+                    object.remove_element(tag);
+                }
+                continue;
+            }
+            let replacement = match self.actions.get(&tag) {
+                None if vr == VR::SQ => {
+                    object.update_value(tag, |value| {
+                        for item in value.items_mut().into_iter().flatten() {
+                            self.apply(item, pseudonym);
+                        }
+                    });
+                    continue;
+                }
+                None => continue,
+                Some(Action::Remove) => None,
+                Some(Action::Empty) => Some(PrimitiveValue::Empty),
+                Some(Action::Pseudonym) => Some(PrimitiveValue::from(pseudonym)),
+                Some(Action::Uid) => {
+                    let uid = self.uid(&original);
+                    object.put(DataElement::new(tag, vr, PrimitiveValue::from(uid)));
+                    continue;
+                }
+            };
+            if vr != VR::SQ && !original.is_empty() {
+                self.mapping
+                    .patients
+                    .entry(pseudonym.to_string())
+                    .or_default()
+                    .entry(keyword(tag))
+                    .or_insert(original);
+            }
+            match replacement {
+                Some(value) => {
+                    object.put(DataElement::new(tag, vr, value));
+                }
+                None => {
+                    object.remove_element(tag);
+                }
+            }
+        }
+    }
+
+    /// De-identifies the DICOM file at `path` in place. Files that cannot be
+    /// parsed are deleted, their content is unknown, and `false` is returned.
+    pub fn deidentify_file<P: AsRef<Path>>(&mut self, path: P) -> Result<bool> {
+        let path = path.as_ref();
+        let mut object = match OpenFileOptions::new().open_file(path) {
+            Ok(object) => object,
+            Err(error) => {
+                warn!(
+                    "Deleting {}, not a readable DICOM file: {}",
+                    path.display(),
+                    error
+                );
+                fs::remove_file(path)?;
+                return Ok(false);
+            }
+        };
+        let patient_id = object.get(tags::PATIENT_ID).map(text).unwrap_or_default();
+        let pseudonym = self.pseudonym(&patient_id);
+        self.apply(&mut object, &pseudonym);
+        object.put(DataElement::new(
+            tags::PATIENT_IDENTITY_REMOVED,
+            VR::CS,
+            PrimitiveValue::from("YES"),
+        ));
+        object.put(DataElement::new(
+            tags::DEIDENTIFICATION_METHOD,
+            VR::LO,
+            PrimitiveValue::from(METHOD),
+        ));
+        let instance_uid = object.meta().media_storage_sop_instance_uid().to_string();
+        let instance_uid = self.uid(instance_uid.trim_end_matches('\0'));
+        object.update_meta(|meta| meta.media_storage_sop_instance_uid = instance_uid);
+        object.write_to_file(path).map_err(|error| {
+            Error::InvalidDeidentification(format!("failed to write {}: {}", path.display(), error))
+        })?;
+        Ok(true)
+    }
+
+    /// De-identifies every DICOM file below `directory` and removes the DICOMDIR
+    /// indexes, which repeat the patient attributes. Returns the number of files
+    /// de-identified.
+    pub fn deidentify_directory<P: AsRef<Path>>(&mut self, directory: P) -> Result<usize> {
+        let mut count = 0;
+        for entry in WalkDir::new(directory).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if entry.file_name().eq_ignore_ascii_case(DICOMDIR) {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            if self.deidentify_file(entry.path())? {
+                count += 1;
+            }
+        }
+        info!("De-identified {} DICOM files", count);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::tests::write_slice;
+
+    #[test]
+    fn test_deidentify_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("IM0001");
+        write_slice(
+            &path,
+            "1.2.3",
+            "1.2.3.4",
+            [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+            &[
+                DataElement::new(tags::PATIENT_NAME, VR::PN, "Doe^Jane"),
+                DataElement::new(tags::PATIENT_ID, VR::LO, "12345"),
+                DataElement::new(tags::PATIENT_AGE, VR::AS, "031Y"),
+                DataElement::new(tags::INSTITUTION_NAME, VR::LO, "General"),
+                DataElement::new(Tag(0x0029, 0x1010), VR::LO, "private"),
+                DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+            ],
+        );
+        let notes = directory.path().join("notes.txt");
+        fs::write(&notes, "Doe^Jane").unwrap();
+        let settings = Deidentification {
+            enable: true,
+            keep: vec!["PatientAge".to_string()],
+            ..Deidentification::default()
+        };
+        let mut deidentifier = Deidentifier::new(&settings, "job", Mapping::default()).unwrap();
+        assert_eq!(
+            deidentifier.deidentify_directory(directory.path()).unwrap(),
+            1
+        );
+        assert!(!notes.exists());
+
+        let object = dicom_object::open_file(&path).unwrap();
+        let value = |tag| object.get(tag).map(text);
+        assert_eq!(value(tags::PATIENT_NAME).as_deref(), Some("job-1"));
+        assert_eq!(value(tags::PATIENT_ID).as_deref(), Some("job-1"));
+        assert_eq!(value(tags::PATIENT_AGE).as_deref(), Some("031Y"));
+        assert_eq!(value(tags::SERIES_DESCRIPTION).as_deref(), Some("T2 HASTE"));
+        assert_eq!(value(tags::INSTITUTION_NAME), None);
+        assert_eq!(value(Tag(0x0029, 0x1010)), None);
+        assert_eq!(
+            value(tags::PATIENT_IDENTITY_REMOVED).as_deref(),
+            Some("YES")
+        );
+        let series_uid = value(tags::SERIES_INSTANCE_UID).unwrap();
+        assert!(series_uid.starts_with(UUID_ROOT));
+        assert_eq!(
+            object
+                .meta()
+                .media_storage_sop_instance_uid()
+                .trim_end_matches('\0'),
+            value(tags::SOP_INSTANCE_UID).unwrap()
+        );
+
+        let mapping = deidentifier.mapping();
+        assert_eq!(mapping.uids["1.2.3.4"], series_uid);
+        assert_eq!(mapping.patients["job-1"]["PatientName"], "Doe^Jane");
+        assert_eq!(mapping.patients["job-1"]["PatientID"], "12345");
+        assert_eq!(mapping.patients["job-1"]["InstitutionName"], "General");
+
+        deidentifier.deidentify_directory(directory.path()).unwrap();
+        let object = dicom_object::open_file(&path).unwrap();
+        let value = |tag| object.get(tag).map(text);
+        assert_eq!(value(tags::PATIENT_ID).as_deref(), Some("job-1"));
+        assert_eq!(value(tags::SERIES_INSTANCE_UID), Some(series_uid));
+        assert_eq!(deidentifier.mapping().patients.len(), 1);
+
+        let settings = Deidentification {
+            keep: vec!["NotAnAttribute".to_string()],
+            ..Deidentification::default()
+        };
+        assert!(matches!(
+            Deidentifier::new(&settings, "job", Mapping::default()),
+            Err(Error::InvalidDeidentification(_))
+        ));
+    }
+}
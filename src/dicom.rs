@@ -0,0 +1,29 @@
+use dicom_core::Tag;
+use dicom_object::DefaultDicomObject;
+
+/// Root of UUID derived UIDs, see PS3.5 B.2.
+pub const UUID_ROOT: &str = "2.25.";
+
+/// New UID under the UUID root, unique without a registered root.
+pub fn new_uid() -> String {
+    format!("{}{}", UUID_ROOT, ulid::Ulid::new().0)
+}
+
+/// Value of `tag` without padding, `None` when missing or empty.
+pub fn string(object: &DefaultDicomObject, tag: Tag) -> Option<String> {
+    let value = object.element_opt(tag).ok()??.to_str().ok()?;
+    let value = value.trim_end_matches(['\0', ' ']).trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Numeric values of `tag`, `None` when missing or not numeric.
+pub fn floats(object: &DefaultDicomObject, tag: Tag) -> Option<Vec<f64>> {
+    object.element_opt(tag).ok()??.to_multi_float64().ok()
+}
+
+/// Slice normal, the cross product of the row and column direction cosines
+/// of ImageOrientationPatient. Not normalized.
+pub fn normal(cosines: [f64; 6]) -> [f64; 3] {
+    let [rx, ry, rz, cx, cy, cz] = cosines;
+    [ry * cz - rz * cy, rz * cx - rx * cz, rx * cy - ry * cx]
+}
@@ -0,0 +1,165 @@
+use dicom_dictionary_std::tags;
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::niftymic::{Error, Result};
+
+pub const DICOMDIR: &str = "DICOMDIR";
+
+/// Files referenced by the directory records of `dicomdir`, relative to its folder.
+pub fn referenced_files(dicomdir: &Path) -> Result<Vec<PathBuf>> {
+    let object = dicom_object::open_file(dicomdir).map_err(|error| {
+        Error::FailedToReadDicom(dicomdir.display().to_string(), error.to_string())
+    })?;
+    let Some(records) = object
+        .element_opt(tags::DIRECTORY_RECORD_SEQUENCE)
+        .ok()
+        .flatten()
+        .and_then(|element| element.items())
+    else {
+        return Ok(Vec::new());
+    };
+    let mut files = Vec::new();
+    for record in records {
+        let Ok(Some(element)) = record.element_opt(tags::REFERENCED_FILE_ID) else {
+            continue;
+        };
+        if let Ok(components) = element.to_multi_str() {
+            files.push(components.iter().map(|part| part.trim()).collect());
+        }
+    }
+    Ok(files)
+}
+
+/// Key used to match a referenced file ID against a file on disk, ignoring the
+/// case changes introduced by ISO 9660 media.
+fn match_key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_lowercase())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// For every DICOMDIR below `directory`, removes the files of its folder it does
+/// not reference, so viewers and reports shipped on the media are not converted.
+/// Returns the number of removed files.
+pub fn prune_unreferenced(directory: &Path) -> Result<usize> {
+    let dicomdirs: Vec<PathBuf> = WalkDir::new(directory)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.file_name().eq_ignore_ascii_case(DICOMDIR))
+        .map(|entry| entry.into_path())
+        .collect();
+    let mut removed = 0;
+    for dicomdir in dicomdirs {
+        let root = dicomdir.parent().unwrap_or(directory);
+        let referenced: HashSet<String> = match referenced_files(&dicomdir) {
+            Ok(files) => files.iter().map(|file| match_key(file)).collect(),
+            Err(error) => {
+                warn!("Ignoring {}: {}", dicomdir.display(), error);
+                continue;
+            }
+        };
+        let files: Vec<PathBuf> = WalkDir::new(root)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && entry.path() != dicomdir)
+            .map(|entry| entry.into_path())
+            .collect();
+        let is_referenced =
+            |file: &PathBuf| referenced.contains(&match_key(file.strip_prefix(root).unwrap()));
+        if !files.iter().any(is_referenced) {
+            warn!(
+                "{} references none of the files next to it, keeping them all",
+                dicomdir.display()
+            );
+            continue;
+        }
+        for file in files.iter().filter(|file| !is_referenced(file)) {
+            fs::remove_file(file)?;
+            removed += 1;
+        }
+        info!(
+            "Kept the {} images referenced by {}",
+            referenced.len(),
+            dicomdir.display()
+        );
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::{value::DataSetSequence, DataElement, PrimitiveValue, VR};
+    use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+
+    use super::*;
+
+    fn write_dicomdir(path: &Path, references: &[&[&str]]) {
+        let records: Vec<InMemDicomObject> = references
+            .iter()
+            .map(|reference| {
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(
+                        tags::DIRECTORY_RECORD_TYPE,
+                        VR::CS,
+                        PrimitiveValue::from("IMAGE"),
+                    ),
+                    DataElement::new(
+                        tags::REFERENCED_FILE_ID,
+                        VR::CS,
+                        PrimitiveValue::Strs(reference.iter().map(|s| s.to_string()).collect()),
+                    ),
+                ])
+            })
+            .collect();
+        let object = InMemDicomObject::from_element_iter([DataElement::new(
+            tags::DIRECTORY_RECORD_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(records),
+        )]);
+        object
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid("1.2.840.10008.1.3.10")
+                    .media_storage_sop_instance_uid("1.2.3.4")
+                    .transfer_syntax("1.2.840.10008.1.2.1"),
+            )
+            .unwrap()
+            .write_to_file(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_prune_unreferenced() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("cd");
+        fs::create_dir_all(root.join("dicom/st000")).unwrap();
+        fs::create_dir_all(root.join("viewer")).unwrap();
+        fs::write(root.join("dicom/st000/im000"), "image").unwrap();
+        fs::write(root.join("dicom/st000/im001"), "image").unwrap();
+        fs::write(root.join("viewer/viewer.exe"), "binary").unwrap();
+        write_dicomdir(
+            &root.join("DICOMDIR"),
+            &[&["DICOM", "ST000", "IM000"], &["DICOM", "ST000", "IM001"]],
+        );
+
+        assert_eq!(
+            referenced_files(&root.join("DICOMDIR")).unwrap(),
+            vec![
+                PathBuf::from("DICOM/ST000/IM000"),
+                PathBuf::from("DICOM/ST000/IM001")
+            ]
+        );
+        assert_eq!(prune_unreferenced(directory.path()).unwrap(), 1);
+        assert!(root.join("dicom/st000/im001").is_file());
+        assert!(!root.join("viewer/viewer.exe").exists());
+    }
+}
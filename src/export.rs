@@ -0,0 +1,686 @@
+use dicom_core::{value::DataSetSequence, DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::tags;
+use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+use log::info;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    dicom::{new_uid, normal},
+    nifti::{self, Header},
+    niftymic::{Error, Result},
+    study::SERIES_DESCRIPTION,
+};
+
+const MR_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.4";
+const ENHANCED_MR_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.4.1";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+/// Intensity percentiles spanned by the default window.
+const WINDOW_PERCENTILES: [f64; 2] = [0.01, 0.99];
+
+/// Decimal string of at most 16 characters, as DS requires, rounded to 6 decimals.
+fn decimal(value: f64) -> String {
+    // No negative zero.
+    let value = value + 0.0;
+    (0..=6)
+        .rev()
+        .map(|precision| format!("{:.*}", precision, value))
+        .map(|text| match text.contains('.') {
+            true => text.trim_end_matches('0').trim_end_matches('.').to_string(),
+            false => text,
+        })
+        .find(|text| text.len() <= 16)
+        .unwrap_or_else(|| format!("{:.6e}", value))
+}
+
+fn decimals(values: &[f64]) -> PrimitiveValue {
+    PrimitiveValue::Strs(values.iter().map(|value| decimal(*value)).collect())
+}
+
+fn sequence(items: Vec<InMemDicomObject>) -> DataSetSequence<InMemDicomObject> {
+    DataSetSequence::from(items)
+}
+
+/// Slice geometry of the volume in DICOM patient coordinates (LPS).
+#[derive(Debug, Clone, PartialEq)]
+struct Geometry {
+    /// Voxel index to LPS millimetre transform.
+    affine: [[f64; 4]; 3],
+    /// Row and column direction cosines, ImageOrientationPatient.
+    orientation: [f64; 6],
+    /// Spacing between rows and between columns, PixelSpacing.
+    pixel_spacing: [f64; 2],
+    slice_spacing: f64,
+}
+
+impl Geometry {
+    fn new(header: &Header) -> Geometry {
+        // NIfTI uses RAS, DICOM LPS.
+        let mut affine = header.affine;
+        for value in affine[..2].iter_mut().flatten() {
+            *value = -*value;
+        }
+        let column = |axis: usize| [affine[0][axis], affine[1][axis], affine[2][axis]];
+        let norm = |vector: [f64; 3]| vector.iter().map(|value| value * value).sum::<f64>().sqrt();
+        let unit = |vector: [f64; 3]| {
+            let length = norm(vector);
+            vector.map(|value| if length > 0.0 { value / length } else { 0.0 })
+        };
+        let [rx, ry, rz] = unit(column(0));
+        let [cx, cy, cz] = unit(column(1));
+        Geometry {
+            affine,
+            orientation: [rx, ry, rz, cx, cy, cz],
+            pixel_spacing: [norm(column(1)), norm(column(0))],
+            slice_spacing: norm(column(2)),
+        }
+    }
+
+    /// ImagePositionPatient of the first voxel of `slice`.
+    fn position(&self, slice: usize) -> [f64; 3] {
+        self.affine.map(|row| row[2] * slice as f64 + row[3])
+    }
+
+    /// Distance of `slice` from the origin along the slice normal, SliceLocation.
+    fn location(&self, slice: usize) -> f64 {
+        let normal = normal(self.orientation);
+        let position = self.position(slice);
+        (0..3).map(|axis| position[axis] * normal[axis]).sum()
+    }
+}
+
+/// Linear mapping of the intensities to unsigned 16-bit stored values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rescale {
+    intercept: f64,
+    slope: f64,
+}
+
+impl Rescale {
+    /// Identity for integer intensities fitting 16 bits, otherwise the full
+    /// stored range spans the intensities.
+    fn new(voxels: &[f32]) -> Rescale {
+        let finite = voxels.iter().filter(|value| value.is_finite());
+        let min = finite.clone().copied().fold(f32::INFINITY, f32::min) as f64;
+        let max = finite.clone().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+        if min > max {
+            return Rescale {
+                intercept: 0.0,
+                slope: 1.0,
+            };
+        }
+        let integral = finite.clone().all(|value| value.fract() == 0.0);
+        if integral && min >= 0.0 && max <= u16::MAX as f64 {
+            Rescale {
+                intercept: 0.0,
+                slope: 1.0,
+            }
+        } else {
+            Rescale {
+                intercept: min,
+                slope: if max > min {
+                    (max - min) / u16::MAX as f64
+                } else {
+                    1.0
+                },
+            }
+        }
+    }
+
+    fn stored(&self, value: f32) -> u16 {
+        if !value.is_finite() {
+            return 0;
+        }
+        ((value as f64 - self.intercept) / self.slope)
+            .round()
+            .clamp(0.0, u16::MAX as f64) as u16
+    }
+}
+
+/// WindowCenter and WindowWidth spanning the window percentiles of `voxels`.
+fn window(voxels: &[f32]) -> [f64; 2] {
+    let mut sorted: Vec<f32> = voxels.iter().copied().filter(|v| v.is_finite()).collect();
+    if sorted.is_empty() {
+        return [0.0, 1.0];
+    }
+    sorted.sort_by(f32::total_cmp);
+    let [low, high] = WINDOW_PERCENTILES
+        .map(|percentile| sorted[((sorted.len() - 1) as f64 * percentile) as usize] as f64);
+    [(low + high) / 2.0, (high - low).max(1.0)]
+}
+
+/// Volume being written and the identifiers shared by its images.
+struct Volume {
+    header: Header,
+    voxels: Vec<f32>,
+    geometry: Geometry,
+    rescale: Rescale,
+    window: [f64; 2],
+    study_instance_uid: String,
+    series_instance_uid: String,
+    frame_of_reference_uid: String,
+}
+
+impl Volume {
+    fn read(path: &Path) -> Result<Volume> {
+        let (header, voxels) = nifti::read_volume(path)?;
+        Ok(Volume {
+            geometry: Geometry::new(&header),
+            rescale: Rescale::new(&voxels),
+            window: window(&voxels),
+            header,
+            voxels,
+            study_instance_uid: new_uid(),
+            series_instance_uid: new_uid(),
+            frame_of_reference_uid: new_uid(),
+        })
+    }
+
+    fn slice_size(&self) -> usize {
+        self.header.dimensions[0] * self.header.dimensions[1]
+    }
+
+    fn pixel_data(&self, voxels: &[f32]) -> PrimitiveValue {
+        PrimitiveValue::U16(
+            voxels
+                .iter()
+                .map(|value| self.rescale.stored(*value))
+                .collect(),
+        )
+    }
+
+    /// Patient, study, series, frame of reference and image pixel attributes
+    /// common to every image. Patient and study are filled in afterwards.
+    fn common(&self, sop_class_uid: &str, sop_instance_uid: &str) -> InMemDicomObject {
+        let [columns, rows, _] = self.header.dimensions;
+        let mut object = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, sop_class_uid),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_instance_uid),
+            DataElement::new(tags::MODALITY, VR::CS, "MR"),
+            DataElement::new(
+                tags::SOFTWARE_VERSIONS,
+                VR::LO,
+                concat!("niftymic_bot ", env!("CARGO_PKG_VERSION")),
+            ),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                self.study_instance_uid.as_str(),
+            ),
+            DataElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                self.series_instance_uid.as_str(),
+            ),
+            DataElement::new(tags::SERIES_DESCRIPTION, VR::LO, SERIES_DESCRIPTION),
+            DataElement::new(
+                tags::FRAME_OF_REFERENCE_UID,
+                VR::UI,
+                self.frame_of_reference_uid.as_str(),
+            ),
+            DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)),
+            DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2"),
+            DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(rows as u16)),
+            DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(columns as u16)),
+            DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(16_u16)),
+            DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(16_u16)),
+            DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(15_u16)),
+            DataElement::new(
+                tags::PIXEL_REPRESENTATION,
+                VR::US,
+                PrimitiveValue::from(0_u16),
+            ),
+        ]);
+        for (tag, vr) in [
+            (tags::PATIENT_NAME, VR::PN),
+            (tags::PATIENT_ID, VR::LO),
+            (tags::PATIENT_BIRTH_DATE, VR::DA),
+            (tags::PATIENT_SEX, VR::CS),
+            (tags::STUDY_DATE, VR::DA),
+            (tags::STUDY_TIME, VR::TM),
+            (tags::STUDY_ID, VR::SH),
+            (tags::ACCESSION_NUMBER, VR::SH),
+            (tags::REFERRING_PHYSICIAN_NAME, VR::PN),
+            (tags::SERIES_NUMBER, VR::IS),
+            (tags::MANUFACTURER, VR::LO),
+            (tags::POSITION_REFERENCE_INDICATOR, VR::LO),
+        ] {
+            object.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+        }
+        object
+    }
+
+    /// Classic MR image of `slice`.
+    fn slice(&self, slice: usize) -> InMemDicomObject {
+        let sop_instance_uid = new_uid();
+        let mut object = self.common(MR_IMAGE_STORAGE, &sop_instance_uid);
+        let size = self.slice_size();
+        for element in [
+            DataElement::new(
+                tags::IMAGE_TYPE,
+                VR::CS,
+                PrimitiveValue::Strs(
+                    ["DERIVED", "SECONDARY"]
+                        .map(String::from)
+                        .into_iter()
+                        .collect(),
+                ),
+            ),
+            DataElement::new(tags::INSTANCE_NUMBER, VR::IS, (slice + 1).to_string()),
+            DataElement::new(tags::SCANNING_SEQUENCE, VR::CS, "RM"),
+            DataElement::new(tags::SEQUENCE_VARIANT, VR::CS, "NONE"),
+            DataElement::new(tags::SCAN_OPTIONS, VR::CS, PrimitiveValue::Empty),
+            DataElement::new(tags::MR_ACQUISITION_TYPE, VR::CS, "3D"),
+            DataElement::new(tags::ECHO_TIME, VR::DS, PrimitiveValue::Empty),
+            DataElement::new(tags::ECHO_TRAIN_LENGTH, VR::IS, PrimitiveValue::Empty),
+            DataElement::new(
+                tags::IMAGES_IN_ACQUISITION,
+                VR::IS,
+                self.header.dimensions[2].to_string(),
+            ),
+            DataElement::new(
+                tags::IMAGE_POSITION_PATIENT,
+                VR::DS,
+                decimals(&self.geometry.position(slice)),
+            ),
+            DataElement::new(
+                tags::IMAGE_ORIENTATION_PATIENT,
+                VR::DS,
+                decimals(&self.geometry.orientation),
+            ),
+            DataElement::new(
+                tags::PIXEL_SPACING,
+                VR::DS,
+                decimals(&self.geometry.pixel_spacing),
+            ),
+            DataElement::new(
+                tags::SLICE_THICKNESS,
+                VR::DS,
+                decimal(self.geometry.slice_spacing),
+            ),
+            DataElement::new(
+                tags::SPACING_BETWEEN_SLICES,
+                VR::DS,
+                decimal(self.geometry.slice_spacing),
+            ),
+            DataElement::new(
+                tags::SLICE_LOCATION,
+                VR::DS,
+                decimal(self.geometry.location(slice)),
+            ),
+            DataElement::new(
+                tags::RESCALE_INTERCEPT,
+                VR::DS,
+                decimal(self.rescale.intercept),
+            ),
+            DataElement::new(tags::RESCALE_SLOPE, VR::DS, decimal(self.rescale.slope)),
+            DataElement::new(tags::RESCALE_TYPE, VR::LO, "US"),
+            DataElement::new(tags::WINDOW_CENTER, VR::DS, decimal(self.window[0])),
+            DataElement::new(tags::WINDOW_WIDTH, VR::DS, decimal(self.window[1])),
+            DataElement::new(
+                tags::PIXEL_DATA,
+                VR::OW,
+                self.pixel_data(&self.voxels[size * slice..size * (slice + 1)]),
+            ),
+        ] {
+            object.put(element);
+        }
+        object
+    }
+
+    /// Enhanced MR image holding every slice as a frame.
+    fn multiframe(&self) -> InMemDicomObject {
+        let sop_instance_uid = new_uid();
+        let mut object = self.common(ENHANCED_MR_IMAGE_STORAGE, &sop_instance_uid);
+        let frames = self.header.dimensions[2];
+        let image_type = || {
+            PrimitiveValue::Strs(
+                ["DERIVED", "PRIMARY", "VOLUME", "NONE"]
+                    .map(String::from)
+                    .into_iter()
+                    .collect(),
+            )
+        };
+        let dimension_organization_uid = new_uid();
+        let shared = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::PIXEL_MEASURES_SEQUENCE,
+                VR::SQ,
+                sequence(vec![InMemDicomObject::from_element_iter([
+                    DataElement::new(
+                        tags::PIXEL_SPACING,
+                        VR::DS,
+                        decimals(&self.geometry.pixel_spacing),
+                    ),
+                    DataElement::new(
+                        tags::SLICE_THICKNESS,
+                        VR::DS,
+                        decimal(self.geometry.slice_spacing),
+                    ),
+                    DataElement::new(
+                        tags::SPACING_BETWEEN_SLICES,
+                        VR::DS,
+                        decimal(self.geometry.slice_spacing),
+                    ),
+                ])]),
+            ),
+            DataElement::new(
+                tags::PLANE_ORIENTATION_SEQUENCE,
+                VR::SQ,
+                sequence(vec![InMemDicomObject::from_element_iter([
+                    DataElement::new(
+                        tags::IMAGE_ORIENTATION_PATIENT,
+                        VR::DS,
+                        decimals(&self.geometry.orientation),
+                    ),
+                ])]),
+            ),
+            DataElement::new(
+                tags::PIXEL_VALUE_TRANSFORMATION_SEQUENCE,
+                VR::SQ,
+                sequence(vec![InMemDicomObject::from_element_iter([
+                    DataElement::new(
+                        tags::RESCALE_INTERCEPT,
+                        VR::DS,
+                        decimal(self.rescale.intercept),
+                    ),
+                    DataElement::new(tags::RESCALE_SLOPE, VR::DS, decimal(self.rescale.slope)),
+                    DataElement::new(tags::RESCALE_TYPE, VR::LO, "US"),
+                ])]),
+            ),
+            DataElement::new(
+                tags::FRAME_VOILUT_SEQUENCE,
+                VR::SQ,
+                sequence(vec![InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::WINDOW_CENTER, VR::DS, decimal(self.window[0])),
+                    DataElement::new(tags::WINDOW_WIDTH, VR::DS, decimal(self.window[1])),
+                ])]),
+            ),
+            DataElement::new(
+                tags::MR_IMAGE_FRAME_TYPE_SEQUENCE,
+                VR::SQ,
+                sequence(vec![InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::FRAME_TYPE, VR::CS, image_type()),
+                    DataElement::new(tags::PIXEL_PRESENTATION, VR::CS, "MONOCHROME"),
+                    DataElement::new(tags::VOLUMETRIC_PROPERTIES, VR::CS, "VOLUME"),
+                    DataElement::new(tags::VOLUME_BASED_CALCULATION_TECHNIQUE, VR::CS, "NONE"),
+                    DataElement::new(tags::COMPLEX_IMAGE_COMPONENT, VR::CS, "MAGNITUDE"),
+                    DataElement::new(tags::ACQUISITION_CONTRAST, VR::CS, "UNKNOWN"),
+                ])]),
+            ),
+        ]);
+        let per_frame = (0..frames)
+            .map(|frame| {
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(
+                        tags::FRAME_CONTENT_SEQUENCE,
+                        VR::SQ,
+                        sequence(vec![InMemDicomObject::from_element_iter([
+                            DataElement::new(tags::STACK_ID, VR::SH, "1"),
+                            DataElement::new(
+                                tags::IN_STACK_POSITION_NUMBER,
+                                VR::UL,
+                                PrimitiveValue::from(frame as u32 + 1),
+                            ),
+                            DataElement::new(
+                                tags::DIMENSION_INDEX_VALUES,
+                                VR::UL,
+                                PrimitiveValue::from(frame as u32 + 1),
+                            ),
+                        ])]),
+                    ),
+                    DataElement::new(
+                        tags::PLANE_POSITION_SEQUENCE,
+                        VR::SQ,
+                        sequence(vec![InMemDicomObject::from_element_iter([
+                            DataElement::new(
+                                tags::IMAGE_POSITION_PATIENT,
+                                VR::DS,
+                                decimals(&self.geometry.position(frame)),
+                            ),
+                        ])]),
+                    ),
+                ])
+            })
+            .collect();
+        let dimension = |pointer: Tag| {
+            InMemDicomObject::from_element_iter([
+                DataElement::new(
+                    tags::DIMENSION_ORGANIZATION_UID,
+                    VR::UI,
+                    dimension_organization_uid.as_str(),
+                ),
+                DataElement::new(
+                    tags::DIMENSION_INDEX_POINTER,
+                    VR::AT,
+                    PrimitiveValue::Tags([pointer].into_iter().collect()),
+                ),
+                DataElement::new(
+                    tags::FUNCTIONAL_GROUP_POINTER,
+                    VR::AT,
+                    PrimitiveValue::Tags([tags::FRAME_CONTENT_SEQUENCE].into_iter().collect()),
+                ),
+            ])
+        };
+        for element in [
+            DataElement::new(tags::IMAGE_TYPE, VR::CS, image_type()),
+            DataElement::new(tags::INSTANCE_NUMBER, VR::IS, "1"),
+            DataElement::new(tags::CONTENT_QUALIFICATION, VR::CS, "PRODUCT"),
+            DataElement::new(tags::PIXEL_PRESENTATION, VR::CS, "MONOCHROME"),
+            DataElement::new(tags::VOLUMETRIC_PROPERTIES, VR::CS, "VOLUME"),
+            DataElement::new(tags::VOLUME_BASED_CALCULATION_TECHNIQUE, VR::CS, "NONE"),
+            DataElement::new(tags::COMPLEX_IMAGE_COMPONENT, VR::CS, "MAGNITUDE"),
+            DataElement::new(tags::ACQUISITION_CONTRAST, VR::CS, "UNKNOWN"),
+            DataElement::new(tags::BURNED_IN_ANNOTATION, VR::CS, "NO"),
+            DataElement::new(tags::LOSSY_IMAGE_COMPRESSION, VR::CS, "00"),
+            DataElement::new(tags::NUMBER_OF_FRAMES, VR::IS, frames.to_string()),
+        ] {
+            object.put(element);
+        }
+        object.put(DataElement::new(
+            tags::DIMENSION_ORGANIZATION_SEQUENCE,
+            VR::SQ,
+            sequence(vec![InMemDicomObject::from_element_iter([
+                DataElement::new(
+                    tags::DIMENSION_ORGANIZATION_UID,
+                    VR::UI,
+                    dimension_organization_uid.as_str(),
+                ),
+            ])]),
+        ));
+        object.put(DataElement::new(
+            tags::DIMENSION_INDEX_SEQUENCE,
+            VR::SQ,
+            sequence(vec![dimension(tags::IN_STACK_POSITION_NUMBER)]),
+        ));
+        object.put(DataElement::new(
+            tags::ACQUISITION_CONTEXT_SEQUENCE,
+            VR::SQ,
+            sequence(Vec::new()),
+        ));
+        object.put(DataElement::new(
+            tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+            VR::SQ,
+            sequence(vec![shared]),
+        ));
+        object.put(DataElement::new(
+            tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            VR::SQ,
+            sequence(per_frame),
+        ));
+        object.put(DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OW,
+            self.pixel_data(&self.voxels),
+        ));
+        object
+    }
+}
+
+fn write(object: InMemDicomObject, path: &Path) -> Result<()> {
+    let failed = |error: String| Error::FailedToWriteDicom(path.display().to_string(), error);
+    let sop_class_uid = object
+        .element(tags::SOP_CLASS_UID)
+        .ok()
+        .and_then(|element| element.to_str().ok().map(|uid| uid.to_string()))
+        .unwrap_or_default();
+    let sop_instance_uid = object
+        .element(tags::SOP_INSTANCE_UID)
+        .ok()
+        .and_then(|element| element.to_str().ok().map(|uid| uid.to_string()))
+        .unwrap_or_default();
+    object
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(sop_class_uid)
+                .media_storage_sop_instance_uid(sop_instance_uid)
+                .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .map_err(|error| failed(error.to_string()))?
+        .write_to_file(path)
+        .map_err(|error| failed(error.to_string()))
+}
+
+/// Writes the first volume of the NIfTI image at `path` to `directory` as a new
+/// MR series, one image per slice or a single enhanced multi-frame image.
+/// Returns the files written, in slice order.
+pub fn write_series<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    directory: Q,
+    multiframe: bool,
+) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let directory = directory.as_ref();
+    let volume = Volume::read(path)?;
+    let [columns, rows, slices] = volume.header.dimensions;
+    if columns > u16::MAX as usize || rows > u16::MAX as usize {
+        return Err(Error::InvalidNifti(
+            path.display().to_string(),
+            "slices too large for DICOM".to_string(),
+        ));
+    }
+    fs::create_dir_all(directory)?;
+    let files = if multiframe {
+        let file = directory.join("IM00001.dcm");
+        write(volume.multiframe(), &file)?;
+        vec![file]
+    } else {
+        (0..slices)
+            .map(|slice| {
+                let file = directory.join(format!("IM{:05}.dcm", slice + 1));
+                write(volume.slice(slice), &file).map(|()| file)
+            })
+            .collect::<Result<Vec<PathBuf>>>()?
+    };
+    info!(
+        "Wrote {} {}x{}x{} to {} DICOM files",
+        path.display(),
+        columns,
+        rows,
+        slices,
+        files.len()
+    );
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_object::open_file;
+
+    use super::*;
+    use crate::nifti::tests::write_nifti;
+
+    #[test]
+    fn test_decimal() {
+        assert_eq!(decimal(0.5), "0.5");
+        assert_eq!(decimal(-12.0), "-12");
+        assert_eq!(decimal(1.0 / 3.0), "0.333333");
+        assert!(decimal(-123456.789012345).starts_with("-123456.789"));
+        assert!(decimal(-123456.789012345).len() <= 16);
+        assert!(decimal(1e300).len() <= 16);
+    }
+
+    #[test]
+    fn test_rescale() {
+        let rescale = Rescale::new(&[0.0, 3.0, 1200.0]);
+        assert_eq!(
+            rescale,
+            Rescale {
+                intercept: 0.0,
+                slope: 1.0
+            }
+        );
+        let rescale = Rescale::new(&[-1.0, 0.5, 1.0, f32::NAN]);
+        assert_eq!(rescale.intercept, -1.0);
+        assert_eq!(rescale.stored(-1.0), 0);
+        assert_eq!(rescale.stored(1.0), u16::MAX);
+        assert_eq!(rescale.stored(f32::NAN), 0);
+        let stored = rescale.stored(0.5) as f64;
+        assert!((stored * rescale.slope + rescale.intercept - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_write_series() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("srr.nii.gz");
+        write_nifti(&path, [4, 3, 2], [0.5, 0.5, 0.8]);
+
+        let output = directory.path().join("slices");
+        let files = write_series(&path, &output, false).unwrap();
+        assert_eq!(files.len(), 2);
+        let slice = open_file(&files[1]).unwrap();
+        let value = |tag| slice.element(tag).unwrap().to_str().unwrap().to_string();
+        assert_eq!(value(tags::SOP_CLASS_UID), MR_IMAGE_STORAGE);
+        assert_eq!(value(tags::ROWS), "3");
+        assert_eq!(value(tags::COLUMNS), "4");
+        assert_eq!(value(tags::INSTANCE_NUMBER), "2");
+        assert_eq!(value(tags::IMAGE_POSITION_PATIENT), "0\\0\\0.8");
+        assert_eq!(value(tags::IMAGE_ORIENTATION_PATIENT), "-1\\0\\0\\0\\-1\\0");
+        assert_eq!(value(tags::PIXEL_SPACING), "0.5\\0.5");
+        assert_eq!(value(tags::RESCALE_SLOPE), "1");
+        let pixels = slice
+            .element(tags::PIXEL_DATA)
+            .unwrap()
+            .to_multi_int::<u16>()
+            .unwrap();
+        assert_eq!(pixels, (12..24).collect::<Vec<u16>>());
+
+        let output = directory.path().join("enhanced");
+        let files = write_series(&path, &output, true).unwrap();
+        assert_eq!(files.len(), 1);
+        let image = open_file(&files[0]).unwrap();
+        assert_eq!(
+            image
+                .element(tags::SOP_CLASS_UID)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            ENHANCED_MR_IMAGE_STORAGE
+        );
+        assert_eq!(
+            image
+                .element(tags::NUMBER_OF_FRAMES)
+                .unwrap()
+                .to_int::<u32>()
+                .unwrap(),
+            2
+        );
+        let frames = image
+            .element(tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            image
+                .element(tags::PIXEL_DATA)
+                .unwrap()
+                .to_multi_int::<u16>()
+                .unwrap()
+                .len(),
+            24
+        );
+    }
+}
@@ -1,15 +1,41 @@
-use std::path::{Path, PathBuf};
+use log::debug;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use walkdir::WalkDir;
 
-const DICOM_SOURCE_DIRECTORY: &str = "archive";
-const DICOM_OUTPUT_DIRECTORY: &str = "dicom_output";
-const MASKS_SOURCE_DIRECTORY: &str = "masks";
-const NIFTY_SOURCE_DIRECTORY: &str = "nifty";
-const NIFTY_OUTPUT_DIRECTORY: &str = "nifty_output";
+const ARCHIVE_DIRECTORY: &str = "archive";
+const NII_DIRECTORY: &str = "nii";
+const MASKS_DIRECTORY: &str = "masks";
+const OUTPUT_NII_DIRECTORY: &str = "output_nii";
+const OUTPUT_DICOM_DIRECTORY: &str = "output_dicom";
+const JOB_FILE: &str = "job.json";
+const DEIDENTIFICATION_FILE: &str = "deidentification.json";
+const NIFTI_SUFFIXES: [&str; 2] = [".nii", ".nii.gz"];
+
+/// Whether `path` names a NIfTI image, compressed or not.
+pub fn is_nifti<P: AsRef<Path>>(path: P) -> bool {
+    let name = path
+        .as_ref()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    NIFTI_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum FileManagerError {
-    #[error("Failed to canonicalize {0}")]
-    CanonicalizeError(#[source] std::io::Error),
+    #[error("Failed to canonicalize {0}: {1}")]
+    CanonicalizeError(PathBuf, #[source] std::io::Error),
+    #[error("Failed to create {0}: {1}")]
+    CreateError(PathBuf, #[source] std::io::Error),
+    #[error("Failed to clean {0}: {1}")]
+    CleanError(PathBuf, #[source] std::io::Error),
+    #[error("{0} is not inside {1}")]
+    OutsideRoot(PathBuf, PathBuf),
 }
 
 #[derive(Debug, Clone)]
@@ -26,21 +52,64 @@ impl Directory {
         self.path.as_path()
     }
 
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+
     pub fn absolute(&self) -> Result<PathBuf, FileManagerError> {
-        Ok(std::fs::canonicalize(self.as_path())
-            .map_err(FileManagerError::CanonicalizeError)?
-            .clone())
+        std::fs::canonicalize(self.as_path())
+            .map_err(|error| FileManagerError::CanonicalizeError(self.path.clone(), error))
     }
 
     pub fn create_if_not_exists(&self) -> Result<(), FileManagerError> {
-        todo!()
+        fs::create_dir_all(self.as_path())
+            .map_err(|error| FileManagerError::CreateError(self.path.clone(), error))
     }
 
+    /// Every file below the directory, sorted.
     pub fn list_files(&self) -> Vec<String> {
-        todo!()
+        let mut files: Vec<String> = WalkDir::new(self.as_path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.path().display().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Files below the directory whose last extension is `extension`, sorted.
+    pub fn files_with_extension(&self, extension: &str) -> Vec<String> {
+        let files: Vec<String> = self
+            .list_files()
+            .into_iter()
+            .filter(|file| Path::new(file).extension() == Some(OsStr::new(extension)))
+            .collect();
+        for file in &files {
+            debug!("Found {} in {}", file, self.path.display());
+        }
+        files
+    }
+
+    /// NIfTI images below the directory, `.nii` and `.nii.gz`, sorted.
+    pub fn nifti_files(&self) -> Vec<String> {
+        self.list_files()
+            .into_iter()
+            .filter(|file| is_nifti(file))
+            .collect()
+    }
+
+    /// Removes every file below the directory, keeping the directory tree.
+    pub fn clean(&self) -> Result<(), FileManagerError> {
+        for file in self.list_files() {
+            fs::remove_file(&file)
+                .map_err(|error| FileManagerError::CleanError(PathBuf::from(&file), error))?;
+        }
+        Ok(())
     }
 }
 
+/// Layout of a job working directory.
 #[derive(Debug, Clone)]
 pub struct FileManager {
     root: Directory,
@@ -48,45 +117,186 @@ pub struct FileManager {
 
 impl FileManager {
     pub fn new<P: Into<PathBuf>>(root: P) -> Self {
-        let name = ulid::Ulid::new().to_string();
-        let path = root.into().as_path().join(name);
-
         Self {
-            root: Directory::new(&path),
+            root: Directory::new(root),
         }
     }
 
+    /// Job directory under `base_directory` named after `prefix` and a fresh ULID.
+    pub fn generate<P: AsRef<Path>>(base_directory: P, prefix: &str) -> Self {
+        let name = format!("{}-{}", prefix, ulid::Ulid::new());
+        Self::new(base_directory.as_ref().join(name))
+    }
+
+    /// Whether `path` is a job directory, i.e. holds a job state file.
+    pub fn is_job_directory<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref().join(JOB_FILE).is_file()
+    }
+
+    /// Creates the job directory, failing if it already exists, and its subdirectories.
     pub fn create(&self) -> Result<(), FileManagerError> {
-        self.root.create_if_not_exists()?;
-        self.dicom_output().create_if_not_exists()?;
-        self.dicom_source().create_if_not_exists()?;
-        self.masks_output().create_if_not_exists()?;
-        self.nifty_output().create_if_not_exists()?;
-        self.nifty_source().create_if_not_exists()?;
+        fs::create_dir(self.root.as_path())
+            .map_err(|error| FileManagerError::CreateError(self.root.path.clone(), error))?;
+        self.archive().create_if_not_exists()?;
+        self.nii().create_if_not_exists()?;
+        self.masks().create_if_not_exists()?;
+        self.output_nii().create_if_not_exists()?;
+        self.output_dicom().create_if_not_exists()?;
         Ok(())
     }
 
-    pub fn dicom_output(&self) -> Directory {
-        self.subdir_from_root(DICOM_OUTPUT_DIRECTORY)
+    pub fn root(&self) -> &Directory {
+        &self.root
+    }
+
+    pub fn name(&self) -> String {
+        self.root
+            .as_path()
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Creation time encoded in the ULID suffix of the directory name.
+    pub fn created_at(&self) -> Option<SystemTime> {
+        let name = self.name();
+        let (_, suffix) = name.rsplit_once('-')?;
+        ulid::Ulid::from_string(suffix)
+            .ok()
+            .map(|ulid| ulid.datetime())
+    }
+
+    /// Total size in bytes of the files in the job directory.
+    pub fn size(&self) -> u64 {
+        WalkDir::new(self.root.as_path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
     }
 
-    pub fn dicom_source(&self) -> Directory {
-        self.subdir_from_root(DICOM_SOURCE_DIRECTORY)
+    pub fn archive(&self) -> Directory {
+        self.subdir_from_root(ARCHIVE_DIRECTORY)
     }
 
-    pub fn masks_output(&self) -> Directory {
-        self.subdir_from_root(MASKS_SOURCE_DIRECTORY)
+    pub fn nii(&self) -> Directory {
+        self.subdir_from_root(NII_DIRECTORY)
     }
 
-    pub fn nifty_output(&self) -> Directory {
-        self.subdir_from_root(NIFTY_OUTPUT_DIRECTORY)
+    pub fn masks(&self) -> Directory {
+        self.subdir_from_root(MASKS_DIRECTORY)
     }
 
-    pub fn nifty_source(&self) -> Directory {
-        self.subdir_from_root(NIFTY_SOURCE_DIRECTORY)
+    pub fn output_nii(&self) -> Directory {
+        self.subdir_from_root(OUTPUT_NII_DIRECTORY)
+    }
+
+    pub fn output_dicom(&self) -> Directory {
+        self.subdir_from_root(OUTPUT_DICOM_DIRECTORY)
+    }
+
+    pub fn job_file(&self) -> PathBuf {
+        self.root.join(JOB_FILE)
+    }
+
+    /// Original values replaced by the de-identification, for re-identification.
+    pub fn deidentification_file(&self) -> PathBuf {
+        self.root.join(DEIDENTIFICATION_FILE)
+    }
+
+    pub fn nifti_filename(&self) -> String {
+        format!("{}.nii.gz", self.name())
+    }
+
+    pub fn dicom_filename(&self) -> String {
+        format!("{}.zip", self.name())
+    }
+
+    /// Reconstructed volume written by NiftyMIC.
+    pub fn output_nifti(&self) -> PathBuf {
+        self.output_nii().join(self.nifti_filename())
+    }
+
+    /// Archive of the exported DICOM series sent back to the user.
+    pub fn output_archive(&self) -> PathBuf {
+        self.root.join(self.dicom_filename())
+    }
+
+    /// Rewrites `path`, located inside the job directory, as if the job directory was `base`.
+    pub fn rebase<P: AsRef<Path>>(&self, path: P, base: &str) -> Result<String, FileManagerError> {
+        let path = path.as_ref();
+        let relative = path.strip_prefix(self.root.as_path()).map_err(|_| {
+            FileManagerError::OutsideRoot(path.to_path_buf(), self.root.path.clone())
+        })?;
+        Ok(Path::new(base).join(relative).display().to_string())
     }
 
     fn subdir_from_root(&self, subdir: &str) -> Directory {
-        Directory::new(self.root.as_path().join(subdir))
+        Directory::new(self.root.join(subdir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_clean_layout() {
+        let base = tempfile::tempdir().unwrap();
+        let files = FileManager::generate(base.path(), "scan");
+        files.create().unwrap();
+        assert!(files.name().starts_with("scan-"));
+        assert!(files.create().is_err());
+        assert!(!FileManager::is_job_directory(files.root().as_path()));
+
+        fs::write(files.nii().join("b.nii"), "").unwrap();
+        fs::write(files.nii().join("a.nii"), "").unwrap();
+        fs::write(files.nii().join("a.json"), "").unwrap();
+        assert_eq!(files.nii().list_files().len(), 3);
+        assert_eq!(
+            files.nii().files_with_extension("nii"),
+            vec![
+                files.nii().join("a.nii").display().to_string(),
+                files.nii().join("b.nii").display().to_string(),
+            ]
+        );
+        fs::write(files.nii().join("c.NII.GZ"), "").unwrap();
+        assert_eq!(
+            files.nii().nifti_files(),
+            vec![
+                files.nii().join("a.nii").display().to_string(),
+                files.nii().join("b.nii").display().to_string(),
+                files.nii().join("c.NII.GZ").display().to_string(),
+            ]
+        );
+        assert!(!is_nifti("a.nii.json"));
+        files.nii().clean().unwrap();
+        assert!(files.nii().list_files().is_empty());
+        assert!(files.nii().as_path().is_dir());
+        assert!(files.created_at().unwrap() <= SystemTime::now());
+        fs::write(files.nii().join("a.nii"), "12345").unwrap();
+        assert_eq!(files.size(), 5);
+    }
+
+    #[test]
+    fn test_rebase() {
+        let files = FileManager::new("/srv/jobs/scan-1");
+        assert_eq!(
+            files
+                .rebase(files.nii().join("a.nii"), "/app/data")
+                .unwrap(),
+            "/app/data/nii/a.nii"
+        );
+        assert_eq!(
+            files.output_nifti().display().to_string(),
+            "/srv/jobs/scan-1/output_nii/scan-1.nii.gz"
+        );
+        assert!(matches!(
+            files.rebase("/tmp/a.nii", "/app/data"),
+            Err(FileManagerError::OutsideRoot(_, _))
+        ));
+        assert!(files.root().absolute().is_err());
     }
 }
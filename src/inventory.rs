@@ -0,0 +1,358 @@
+use dicom_dictionary_std::tags;
+use dicom_object::OpenFileOptions;
+use log::debug;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    config::Selection,
+    dicom::{floats, normal, string},
+    niftymic::{Error, Result},
+};
+
+/// Acquisition plane, from the normal of the image orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Axial,
+    Coronal,
+    Sagittal,
+}
+
+impl Orientation {
+    /// Plane whose normal is closest to the normal of the row and column
+    /// direction cosines of ImageOrientationPatient.
+    pub fn from_cosines(cosines: &[f64]) -> Option<Orientation> {
+        let [x, y, z] = normal(cosines.try_into().ok()?).map(f64::abs);
+        if x == 0.0 && y == 0.0 && z == 0.0 {
+            None
+        } else if z >= x && z >= y {
+            Some(Orientation::Axial)
+        } else if y >= x {
+            Some(Orientation::Coronal)
+        } else {
+            Some(Orientation::Sagittal)
+        }
+    }
+}
+
+impl fmt::Display for Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Orientation::Axial => write!(f, "axial"),
+            Orientation::Coronal => write!(f, "coronal"),
+            Orientation::Sagittal => write!(f, "sagittal"),
+        }
+    }
+}
+
+/// Images of the input sharing a StudyInstanceUID and SeriesInstanceUID.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Series {
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub series_number: Option<i32>,
+    pub modality: Option<String>,
+    pub series_description: Option<String>,
+    /// ScanningSequence, e.g. `SE` or `GR`.
+    pub scanning_sequence: Option<String>,
+    /// Vendor sequence name, e.g. `*haste2d1`.
+    pub sequence_name: Option<String>,
+    pub orientation: Option<Orientation>,
+    /// Number of slices, frames of multi-frame images included.
+    pub slices: usize,
+    /// Row and column spacing in millimetres.
+    pub pixel_spacing: Option<[f64; 2]>,
+    pub slice_thickness: Option<f64>,
+    /// Whether the series is converted and reconstructed.
+    #[serde(default)]
+    pub selected: bool,
+    /// Files of the series, not kept in the job state file.
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+impl fmt::Display for Series {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} \"{}\" {}, {} slices",
+            self.series_number
+                .map(|number| number.to_string())
+                .unwrap_or_else(|| "?".to_string()),
+            self.modality.as_deref().unwrap_or("?"),
+            self.series_description.as_deref().unwrap_or(""),
+            self.orientation
+                .map(|orientation| orientation.to_string())
+                .unwrap_or_else(|| "unknown orientation".to_string()),
+            self.slices
+        )?;
+        if let Some([row, column]) = self.pixel_spacing {
+            write!(f, ", {:.2}x{:.2} mm", row, column)?;
+        }
+        if let Some(thickness) = self.slice_thickness {
+            write!(f, ", {:.2} mm thick", thickness)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups the DICOM files below `directory` by study and series, skipping files
+/// which are not DICOM. Pixel data is not read.
+pub fn scan<P: AsRef<Path>>(directory: P) -> Result<Vec<Series>> {
+    let mut series: BTreeMap<(String, String), Series> = BTreeMap::new();
+    for entry in WalkDir::new(directory).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let object = match OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(entry.path())
+        {
+            Ok(object) => object,
+            Err(error) => {
+                debug!("Skipping {}: {}", entry.path().display(), error);
+                continue;
+            }
+        };
+        let (Some(study), Some(series_uid)) = (
+            string(&object, tags::STUDY_INSTANCE_UID),
+            string(&object, tags::SERIES_INSTANCE_UID),
+        ) else {
+            debug!(
+                "Skipping {}: no study or series UID",
+                entry.path().display()
+            );
+            continue;
+        };
+        let frames = string(&object, tags::NUMBER_OF_FRAMES)
+            .and_then(|frames| frames.parse().ok())
+            .unwrap_or(1);
+        let current = series
+            .entry((study.clone(), series_uid.clone()))
+            .or_insert_with(|| Series {
+                study_instance_uid: study,
+                series_instance_uid: series_uid,
+                series_number: string(&object, tags::SERIES_NUMBER)
+                    .and_then(|number| number.parse().ok()),
+                modality: string(&object, tags::MODALITY),
+                series_description: string(&object, tags::SERIES_DESCRIPTION),
+                scanning_sequence: string(&object, tags::SCANNING_SEQUENCE),
+                sequence_name: string(&object, tags::SEQUENCE_NAME),
+                orientation: floats(&object, tags::IMAGE_ORIENTATION_PATIENT)
+                    .and_then(|cosines| Orientation::from_cosines(&cosines)),
+                slices: 0,
+                pixel_spacing: floats(&object, tags::PIXEL_SPACING)
+                    .and_then(|spacing| <[f64; 2]>::try_from(spacing).ok()),
+                slice_thickness: floats(&object, tags::SLICE_THICKNESS)
+                    .and_then(|thickness| thickness.first().copied()),
+                selected: true,
+                files: Vec::new(),
+            });
+        current.slices += frames;
+        current.files.push(entry.into_path());
+    }
+    let mut series: Vec<Series> = series.into_values().collect();
+    series.sort_by(|a, b| {
+        (&a.study_instance_uid, a.series_number).cmp(&(&b.study_instance_uid, b.series_number))
+    });
+    Ok(series)
+}
+
+fn regex(rule: &Option<String>) -> Result<Option<Regex>> {
+    rule.as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(|error| Error::InvalidSelection(error.to_string()))
+}
+
+/// Checks that the regexes of `rules` compile.
+pub fn validate(rules: &Selection) -> Result<()> {
+    regex(&rules.description)?;
+    regex(&rules.sequence)?;
+    Ok(())
+}
+
+/// Marks the series passing every rule of `rules` as selected.
+pub fn select(series: &mut [Series], rules: &Selection) -> Result<()> {
+    let description = regex(&rules.description)?;
+    let sequence = regex(&rules.sequence)?;
+    for series in series.iter_mut() {
+        let sequence_text = [&series.scanning_sequence, &series.sequence_name]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ");
+        series.selected = description.as_ref().map_or(true, |description| {
+            description.is_match(series.series_description.as_deref().unwrap_or_default())
+        }) && sequence
+            .as_ref()
+            .map_or(true, |sequence| sequence.is_match(&sequence_text))
+            && rules
+                .min_slices
+                .map_or(true, |min_slices| series.slices >= min_slices)
+            && (rules.orientations.is_empty()
+                || series
+                    .orientation
+                    .is_some_and(|orientation| rules.orientations.contains(&orientation)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use dicom_core::{DataElement, PrimitiveValue, VR};
+    use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+
+    use super::*;
+
+    /// Writes an MR slice header of `series` in `study` to `path`, with the
+    /// `extra` elements added or replacing the defaults.
+    pub(crate) fn write_slice(
+        path: &Path,
+        study: &str,
+        series: &str,
+        orientation: [f64; 6],
+        extra: &[DataElement<InMemDicomObject>],
+    ) {
+        let mut object = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, "1.2.840.10008.5.1.4.1.1.4"),
+            DataElement::new(tags::MODALITY, VR::CS, "MR"),
+            DataElement::new(tags::SERIES_DESCRIPTION, VR::LO, "T2 HASTE"),
+            DataElement::new(tags::SLICE_THICKNESS, VR::DS, "3.5"),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series),
+            DataElement::new(tags::SERIES_NUMBER, VR::IS, "3"),
+            DataElement::new(
+                tags::IMAGE_ORIENTATION_PATIENT,
+                VR::DS,
+                PrimitiveValue::F64(orientation.into_iter().collect()),
+            ),
+            DataElement::new(
+                tags::PIXEL_SPACING,
+                VR::DS,
+                PrimitiveValue::F64([0.7, 0.8].into_iter().collect()),
+            ),
+        ]);
+        for element in extra {
+            object.put(element.clone());
+        }
+        let instance_uid = object
+            .get(tags::SOP_INSTANCE_UID)
+            .and_then(|element| element.to_str().ok().map(|uid| uid.to_string()))
+            .unwrap_or_else(|| path.display().to_string());
+        object
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.4")
+                    .media_storage_sop_instance_uid(instance_uid)
+                    .transfer_syntax("1.2.840.10008.1.2.1"),
+            )
+            .unwrap()
+            .write_to_file(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_orientation_from_cosines() {
+        assert_eq!(
+            Orientation::from_cosines(&[1.0, 0.0, 0.0, 0.0, 1.0, 0.0]),
+            Some(Orientation::Axial)
+        );
+        assert_eq!(
+            Orientation::from_cosines(&[1.0, 0.0, 0.0, 0.0, 0.1, -0.9]),
+            Some(Orientation::Coronal)
+        );
+        assert_eq!(
+            Orientation::from_cosines(&[0.0, 1.0, 0.0, 0.0, 0.0, -1.0]),
+            Some(Orientation::Sagittal)
+        );
+        assert_eq!(Orientation::from_cosines(&[1.0, 0.0]), None);
+    }
+
+    #[test]
+    fn test_scan_groups_series() {
+        let directory = tempfile::tempdir().unwrap();
+        let axial = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let sagittal = [0.0, 1.0, 0.0, 0.0, 0.0, -1.0];
+        write_slice(&directory.path().join("a1"), "1.2", "1.2.1", axial, &[]);
+        write_slice(&directory.path().join("a2"), "1.2", "1.2.1", axial, &[]);
+        write_slice(&directory.path().join("s1"), "1.2", "1.2.2", sagittal, &[]);
+        std::fs::write(directory.path().join("README.txt"), "not dicom").unwrap();
+
+        let series = scan(directory.path()).unwrap();
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].series_instance_uid, "1.2.1");
+        assert_eq!(series[0].slices, 2);
+        assert_eq!(series[0].files.len(), 2);
+        assert_eq!(series[0].orientation, Some(Orientation::Axial));
+        assert_eq!(series[0].modality.as_deref(), Some("MR"));
+        assert_eq!(series[0].pixel_spacing, Some([0.7, 0.8]));
+        assert_eq!(series[0].slice_thickness, Some(3.5));
+        assert_eq!(series[1].orientation, Some(Orientation::Sagittal));
+        assert!(series.iter().all(|series| series.selected));
+        assert_eq!(
+            series[0].to_string(),
+            "#3 MR \"T2 HASTE\" axial, 2 slices, 0.70x0.80 mm, 3.50 mm thick"
+        );
+    }
+
+    /// Selected HASTE series described and identified by `description`.
+    pub(crate) fn series(description: &str, slices: usize, orientation: Orientation) -> Series {
+        Series {
+            study_instance_uid: "1.2".to_string(),
+            series_instance_uid: description.to_string(),
+            series_number: None,
+            modality: Some("MR".to_string()),
+            series_description: Some(description.to_string()),
+            scanning_sequence: Some("SE".to_string()),
+            sequence_name: Some("*haste2d1".to_string()),
+            orientation: Some(orientation),
+            slices,
+            pixel_spacing: None,
+            slice_thickness: None,
+            selected: true,
+            files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let mut inventory = vec![
+            series("localizer", 3, Orientation::Axial),
+            series("T2 HASTE ax", 30, Orientation::Axial),
+            series("T2 HASTE sag", 30, Orientation::Sagittal),
+            series("body T2 HASTE", 40, Orientation::Coronal),
+        ];
+        let rules = Selection {
+            description: Some("(?i)^t2 haste".to_string()),
+            sequence: Some("haste".to_string()),
+            min_slices: Some(10),
+            orientations: vec![Orientation::Axial, Orientation::Sagittal],
+        };
+        select(&mut inventory, &rules).unwrap();
+        let selected: Vec<bool> = inventory.iter().map(|series| series.selected).collect();
+        assert_eq!(selected, vec![false, true, true, false]);
+
+        select(&mut inventory, &Selection::default()).unwrap();
+        assert!(inventory.iter().all(|series| series.selected));
+        let rules = Selection {
+            description: Some("(".to_string()),
+            ..Selection::default()
+        };
+        assert!(matches!(
+            select(&mut inventory, &rules),
+            Err(Error::InvalidSelection(_))
+        ));
+        assert!(validate(&rules).is_err());
+        assert!(validate(&Selection::default()).is_ok());
+    }
+}
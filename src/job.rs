@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{
+    inventory::Series,
+    niftymic::{Error, Options, Result},
+    study::Study,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum JobState {
+    Received,
+    /// DICOM series listed and the stacks to reconstruct selected.
+    Inspected,
+    Converted,
+    Masked,
+    Reconstructed,
+    Exported,
+    Failed,
+}
+
+impl JobState {
+    pub fn next(&self) -> Option<JobState> {
+        match self {
+            JobState::Received => Some(JobState::Inspected),
+            JobState::Inspected => Some(JobState::Converted),
+            JobState::Converted => Some(JobState::Masked),
+            JobState::Masked => Some(JobState::Reconstructed),
+            JobState::Reconstructed => Some(JobState::Exported),
+            JobState::Exported | JobState::Failed => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub state: JobState,
+    /// Last step that completed successfully, used to resume a failed job.
+    pub completed: JobState,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub output: Option<String>,
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    #[serde(default)]
+    pub options: Option<Options>,
+    #[serde(default)]
+    pub preset: Option<String>,
+    /// Wall-clock seconds spent in each completed step.
+    #[serde(default)]
+    pub durations: BTreeMap<JobState, f64>,
+    /// DICOM series found in the input before conversion.
+    #[serde(default)]
+    pub series: Vec<Series>,
+    /// Patient and study context of the input, carried into the output series.
+    #[serde(default)]
+    pub study: Option<Study>,
+    /// File names of the NIfTI stacks to reconstruct, every stack when unset.
+    #[serde(default)]
+    pub stacks: Option<Vec<String>>,
+    /// Paused until the user picks the series or stacks to reconstruct.
+    #[serde(default)]
+    pub awaiting_selection: bool,
+}
+
+impl Job {
+    pub fn new() -> Job {
+        Job {
+            state: JobState::Received,
+            completed: JobState::Received,
+            error: None,
+            output: None,
+            chat_id: None,
+            options: None,
+            preset: None,
+            durations: BTreeMap::new(),
+            series: Vec::new(),
+            study: None,
+            stacks: None,
+            awaiting_selection: false,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Job> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// State the pipeline restarts from, ignoring a previous failure.
+    pub fn resume_state(&self) -> JobState {
+        self.completed
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state == JobState::Exported
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.state == JobState::Failed
+    }
+
+    pub fn advance(&mut self, state: JobState) {
+        self.state = state;
+        self.completed = state;
+        self.error = None;
+    }
+
+    pub fn selected_series(&self) -> impl Iterator<Item = &Series> {
+        self.series.iter().filter(|series| series.selected)
+    }
+
+    /// Selects exactly the series at the 1-based positions `picks` in `series`.
+    pub fn pick_series(&mut self, picks: &[usize]) -> Result<()> {
+        if let Some(pick) = picks
+            .iter()
+            .find(|pick| **pick == 0 || **pick > self.series.len())
+        {
+            return Err(Error::InvalidSelection(format!(
+                "there is no series {}, pick between 1 and {}",
+                pick,
+                self.series.len()
+            )));
+        }
+        if picks.is_empty() {
+            return Err(Error::InvalidSelection(
+                "pick at least one series".to_string(),
+            ));
+        }
+        for (index, series) in self.series.iter_mut().enumerate() {
+            series.selected = picks.contains(&(index + 1));
+        }
+        Ok(())
+    }
+
+    /// Whether the stack named `name` is reconstructed.
+    pub fn is_stack_selected(&self, name: &str) -> bool {
+        self.stacks
+            .as_ref()
+            .map_or(true, |stacks| stacks.iter().any(|stack| stack == name))
+    }
+
+    /// Adds or removes `name` from the stacks to reconstruct, `all` listing
+    /// every stack available.
+    pub fn toggle_stack(&mut self, name: &str, all: &[String]) {
+        let mut stacks: Vec<String> = all
+            .iter()
+            .filter(|stack| self.is_stack_selected(stack))
+            .cloned()
+            .collect();
+        match stacks.iter().position(|stack| stack == name) {
+            Some(position) => {
+                stacks.remove(position);
+            }
+            None => stacks.push(name.to_string()),
+        }
+        stacks.sort();
+        self.stacks = Some(stacks);
+    }
+
+    /// Records the stacks of `all` currently selected as the final choice, so
+    /// the job no longer waits for one. Returns how many are selected.
+    pub fn confirm_stacks(&mut self, all: &[String]) -> usize {
+        let stacks: Vec<String> = all
+            .iter()
+            .filter(|stack| self.is_stack_selected(stack))
+            .cloned()
+            .collect();
+        let selected = stacks.len();
+        self.stacks = Some(stacks);
+        selected
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        self.state = JobState::Failed;
+        self.error = Some(error.to_string());
+    }
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::inventory::{tests::series, Orientation};
+
+    #[test]
+    fn test_job_state_order() {
+        let mut state = JobState::Received;
+        let mut states = vec![state];
+        while let Some(next) = state.next() {
+            states.push(next);
+            state = next;
+        }
+        assert_eq!(
+            states,
+            vec![
+                JobState::Received,
+                JobState::Inspected,
+                JobState::Converted,
+                JobState::Masked,
+                JobState::Reconstructed,
+                JobState::Exported,
+            ]
+        );
+        assert_eq!(JobState::Failed.next(), None);
+    }
+
+    #[test]
+    fn test_job_fail_keeps_completed_step() {
+        let mut job = Job::new();
+        job.advance(JobState::Converted);
+        job.fail("mask generation failed");
+        assert!(job.is_failed());
+        assert_eq!(job.resume_state(), JobState::Converted);
+        job.advance(JobState::Masked);
+        assert_eq!(job.error, None);
+    }
+
+    #[test]
+    fn test_job_pick_series() {
+        let mut job = Job::new();
+        job.series = vec![
+            series("localizer", 3, Orientation::Axial),
+            series("T2 HASTE ax", 30, Orientation::Axial),
+            series("T2 HASTE sag", 30, Orientation::Sagittal),
+        ];
+        job.pick_series(&[3, 1]).unwrap();
+        let picked: Vec<&str> = job
+            .selected_series()
+            .map(|series| series.series_instance_uid.as_str())
+            .collect();
+        assert_eq!(picked, vec!["localizer", "T2 HASTE sag"]);
+        assert!(matches!(
+            job.pick_series(&[4]),
+            Err(Error::InvalidSelection(_))
+        ));
+        assert!(job.pick_series(&[]).is_err());
+        assert_eq!(job.selected_series().count(), 2);
+    }
+
+    #[test]
+    fn test_job_toggle_stack() {
+        let all = vec!["a.nii".to_string(), "b.nii".to_string()];
+        let mut job = Job::new();
+        assert!(job.is_stack_selected("a.nii"));
+        job.toggle_stack("a.nii", &all);
+        assert_eq!(job.stacks, Some(vec!["b.nii".to_string()]));
+        assert!(!job.is_stack_selected("a.nii"));
+        job.toggle_stack("a.nii", &all);
+        assert_eq!(job.stacks, Some(all));
+    }
+
+    #[test]
+    fn test_job_confirm_default_stacks() {
+        let all = vec!["a.nii".to_string(), "b.nii".to_string()];
+        let mut job = Job::new();
+        assert_eq!(job.stacks, None);
+        assert_eq!(job.confirm_stacks(&all), 2);
+        assert_eq!(job.stacks, Some(all.clone()));
+
+        job.toggle_stack("b.nii", &all);
+        assert_eq!(job.confirm_stacks(&all), 1);
+        job.toggle_stack("a.nii", &all);
+        assert_eq!(job.confirm_stacks(&all), 0);
+    }
+
+    #[test]
+    fn test_job_save_and_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("job.json");
+        let mut job = Job::new();
+        job.advance(JobState::Masked);
+        job.chat_id = Some(42);
+        job.durations.insert(JobState::Masked, 12.5);
+        job.save(&path).unwrap();
+        let loaded = Job::load(&path).unwrap();
+        assert_eq!(loaded.state, JobState::Masked);
+        assert_eq!(loaded.chat_id, Some(42));
+        assert_eq!(loaded.durations.get(&JobState::Masked), Some(&12.5));
+    }
+}
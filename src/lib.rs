@@ -1,5 +1,19 @@
 pub mod archive;
+pub mod cancel;
 pub mod config;
+pub mod container;
+pub mod deidentify;
+pub mod dicom;
+pub mod dicomdir;
+pub mod export;
 pub mod filemgr;
+pub mod inventory;
+pub mod job;
+pub mod nifti;
 pub mod niftymic;
+pub mod preset;
+pub mod progress;
+pub mod queue;
+pub mod retention;
 pub mod spawn;
+pub mod study;
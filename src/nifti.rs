@@ -0,0 +1,456 @@
+use flate2::{read::GzDecoder, write::ZlibEncoder, Compression, Crc};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Read, Write},
+    path::Path,
+};
+
+use crate::niftymic::{Error, Result};
+
+const HEADER_SIZE: usize = 348;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// Intensity percentile mapped to white in thumbnails, brighter voxels are clipped.
+const THUMBNAIL_PERCENTILE: f32 = 0.99;
+
+/// Fields of a NIfTI-1 header needed to read the first volume of an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    /// Size along x, y and z, 1 for missing dimensions.
+    pub dimensions: [usize; 3],
+    /// Voxel size along x, y and z in millimetres.
+    pub spacing: [f32; 3],
+    pub datatype: i16,
+    pub vox_offset: usize,
+    pub scl_slope: f32,
+    pub scl_inter: f32,
+    /// Voxel index to RAS millimetre transform, from the sform, else the qform,
+    /// else the spacing alone.
+    pub affine: [[f64; 4]; 3],
+    little_endian: bool,
+}
+
+impl Header {
+    fn parse(bytes: &[u8], path: &Path) -> Result<Header> {
+        let invalid = |reason: &str| Error::InvalidNifti(path.display().to_string(), reason.into());
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid("truncated header"));
+        }
+        let little_endian = match bytes[0..4].try_into().unwrap() {
+            size if i32::from_le_bytes(size) == HEADER_SIZE as i32 => true,
+            size if i32::from_be_bytes(size) == HEADER_SIZE as i32 => false,
+            _ => return Err(invalid("not a NIfTI-1 header")),
+        };
+        let i16_at = |offset: usize| {
+            let value = bytes[offset..offset + 2].try_into().unwrap();
+            if little_endian {
+                i16::from_le_bytes(value)
+            } else {
+                i16::from_be_bytes(value)
+            }
+        };
+        let f32_at = |offset: usize| {
+            let value = bytes[offset..offset + 4].try_into().unwrap();
+            if little_endian {
+                f32::from_le_bytes(value)
+            } else {
+                f32::from_be_bytes(value)
+            }
+        };
+        let rank = i16_at(40);
+        if !(1..=7).contains(&rank) {
+            return Err(invalid("invalid number of dimensions"));
+        }
+        let mut dimensions = [1; 3];
+        let mut spacing = [1.0; 3];
+        for axis in 0..(rank as usize).min(3) {
+            let size = i16_at(42 + 2 * axis);
+            if size < 1 {
+                return Err(invalid("invalid dimension"));
+            }
+            dimensions[axis] = size as usize;
+            spacing[axis] = f32_at(80 + 4 * axis).abs();
+        }
+        let affine = if i16_at(254) > 0 {
+            [0, 1, 2]
+                .map(|row| [0, 1, 2, 3].map(|column| f32_at(280 + 16 * row + 4 * column) as f64))
+        } else if i16_at(252) > 0 {
+            let [b, c, d, x, y, z] =
+                [256, 260, 264, 268, 272, 276].map(|offset| f32_at(offset) as f64);
+            let a = (1.0 - b * b - c * c - d * d).max(0.0).sqrt();
+            let rotation = [
+                [
+                    a * a + b * b - c * c - d * d,
+                    2.0 * (b * c - a * d),
+                    2.0 * (b * d + a * c),
+                ],
+                [
+                    2.0 * (b * c + a * d),
+                    a * a + c * c - b * b - d * d,
+                    2.0 * (c * d - a * b),
+                ],
+                [
+                    2.0 * (b * d - a * c),
+                    2.0 * (c * d + a * b),
+                    a * a + d * d - b * b - c * c,
+                ],
+            ];
+            let qfac = if f32_at(76) < 0.0 { -1.0 } else { 1.0 };
+            let scale = [
+                spacing[0] as f64,
+                spacing[1] as f64,
+                qfac * spacing[2] as f64,
+            ];
+            let offset = [x, y, z];
+            [0, 1, 2].map(|row| {
+                let [r0, r1, r2] = rotation[row];
+                [r0 * scale[0], r1 * scale[1], r2 * scale[2], offset[row]]
+            })
+        } else {
+            [0, 1, 2].map(|row| {
+                let mut values = [0.0; 4];
+                values[row] = spacing[row] as f64;
+                values
+            })
+        };
+        Ok(Header {
+            dimensions,
+            spacing,
+            datatype: i16_at(70),
+            vox_offset: f32_at(108).max(HEADER_SIZE as f32) as usize,
+            scl_slope: f32_at(112),
+            scl_inter: f32_at(116),
+            affine,
+            little_endian,
+        })
+    }
+
+    /// Reads the header of the `.nii` or `.nii.gz` image at `path`.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Header> {
+        let path = path.as_ref();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        open(path)?
+            .take(HEADER_SIZE as u64)
+            .read_to_end(&mut bytes)?;
+        Header::parse(&bytes, path)
+    }
+
+    fn voxels(&self) -> usize {
+        self.dimensions.iter().product()
+    }
+
+    /// Bytes per voxel of the datatype.
+    fn voxel_size(&self, path: &Path) -> Result<usize> {
+        match self.datatype {
+            2 | 256 => Ok(1),
+            4 | 512 => Ok(2),
+            8 | 16 | 768 => Ok(4),
+            64 => Ok(8),
+            datatype => Err(Error::InvalidNifti(
+                path.display().to_string(),
+                format!("unsupported datatype {}", datatype),
+            )),
+        }
+    }
+
+    /// Converts the raw bytes of `voxels` voxels to scaled intensities.
+    fn decode(&self, data: &[u8], voxels: usize, path: &Path) -> Result<Vec<f32>> {
+        let width = self.voxel_size(path)?;
+        if data.len() < voxels * width {
+            return Err(Error::InvalidNifti(
+                path.display().to_string(),
+                "truncated image data".to_string(),
+            ));
+        }
+        let little_endian = self.little_endian;
+        let bytes = |chunk: &[u8]| -> [u8; 8] {
+            let mut value = [0; 8];
+            if little_endian {
+                value[..chunk.len()].copy_from_slice(chunk);
+            } else {
+                value[8 - chunk.len()..].copy_from_slice(chunk);
+                value.reverse();
+            }
+            value
+        };
+        let (slope, inter) = match self.scl_slope {
+            slope if slope == 0.0 || !slope.is_finite() => (1.0, 0.0),
+            slope => (slope, self.scl_inter),
+        };
+        Ok(data
+            .chunks_exact(width)
+            .take(voxels)
+            .map(|chunk| {
+                let value = bytes(chunk);
+                let value = match self.datatype {
+                    2 => value[0] as f32,
+                    256 => value[0] as i8 as f32,
+                    4 => i16::from_le_bytes([value[0], value[1]]) as f32,
+                    512 => u16::from_le_bytes([value[0], value[1]]) as f32,
+                    8 => i32::from_le_bytes(value[..4].try_into().unwrap()) as f32,
+                    768 => u32::from_le_bytes(value[..4].try_into().unwrap()) as f32,
+                    16 => f32::from_le_bytes(value[..4].try_into().unwrap()),
+                    _ => f64::from_le_bytes(value) as f32,
+                };
+                value * slope + inter
+            })
+            .collect())
+    }
+}
+
+fn open(path: &Path) -> Result<Box<dyn Read>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 2];
+    file.read_exact(&mut magic)?;
+    let file = std::io::Cursor::new(magic).chain(file);
+    if magic == GZIP_MAGIC {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Header and intensities of the first volume of the image at `path`, x varying fastest.
+pub fn read_volume<P: AsRef<Path>>(path: P) -> Result<(Header, Vec<f32>)> {
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    open(path)?.read_to_end(&mut bytes)?;
+    let header = Header::parse(&bytes, path)?;
+    let data = bytes.get(header.vox_offset..).unwrap_or_default();
+    let voxels = header.decode(data, header.voxels(), path)?;
+    Ok((header, voxels))
+}
+
+/// Header and intensities of the middle slice of the image at `path`, reading
+/// no further than that slice.
+fn read_middle_slice(path: &Path) -> Result<(Header, Vec<f32>)> {
+    let mut reader = open(path)?;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE);
+    reader
+        .by_ref()
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut bytes)?;
+    let header = Header::parse(&bytes, path)?;
+    let [width, height, depth] = header.dimensions;
+    let slice_size = width * height * header.voxel_size(path)?;
+    let skip = header.vox_offset - HEADER_SIZE + slice_size * (depth / 2);
+    std::io::copy(&mut reader.by_ref().take(skip as u64), &mut std::io::sink())?;
+    let mut data = Vec::with_capacity(slice_size);
+    reader.take(slice_size as u64).read_to_end(&mut data)?;
+    let voxels = header.decode(&data, width * height, path)?;
+    Ok((header, voxels))
+}
+
+/// A NIfTI stack offered for reconstruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stack {
+    /// File name in the NIfTI directory.
+    pub name: String,
+    pub header: Header,
+}
+
+impl Stack {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Stack> {
+        let path = path.as_ref();
+        Ok(Stack {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            header: Header::read(path)?,
+        })
+    }
+}
+
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [x, y, z] = self.header.dimensions;
+        let [dx, dy, dz] = self.header.spacing;
+        write!(
+            f,
+            "{} {}x{}x{}, {:.2}x{:.2}x{:.2} mm",
+            self.name, x, y, z, dx, dy, dz
+        )
+    }
+}
+
+/// PNG of the middle slice of the image at `path`, anterior up.
+pub fn thumbnail<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let (header, slice) = read_middle_slice(path.as_ref())?;
+    let [width, height, _] = header.dimensions;
+    let mut sorted: Vec<f32> = slice.iter().copied().filter(|v| v.is_finite()).collect();
+    sorted.sort_by(f32::total_cmp);
+    let low = sorted.first().copied().unwrap_or_default();
+    let high = sorted
+        .get(((sorted.len() as f32 * THUMBNAIL_PERCENTILE) as usize).min(sorted.len().max(1) - 1))
+        .copied()
+        .unwrap_or_default();
+    let scale = if high > low {
+        255.0 / (high - low)
+    } else {
+        0.0
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in slice.chunks_exact(width).rev() {
+        pixels.extend(
+            row.iter()
+                .map(|value| ((value - low) * scale).clamp(0.0, 255.0) as u8),
+        );
+    }
+    encode_png(width, height, &pixels)
+}
+
+/// Encodes 8-bit grayscale `pixels`, row by row, as a PNG image.
+pub fn encode_png(width: usize, height: usize, pixels: &[u8]) -> Result<Vec<u8>> {
+    let mut png = PNG_SIGNATURE.to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // Bit depth 8, grayscale, deflate, adaptive filtering, no interlace.
+    header.extend([8, 0, 0, 0, 0]);
+    png_chunk(&mut png, b"IHDR", &header);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pixels.chunks_exact(width.max(1)).take(height) {
+        // Filter type 0, rows stored as is.
+        encoder.write_all(&[0])?;
+        encoder.write_all(row)?;
+    }
+    png_chunk(&mut png, b"IDAT", &encoder.finish()?);
+    png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    /// Writes a compressed little-endian int16 NIfTI-1 image of `dimensions`
+    /// whose voxels hold their index.
+    pub(crate) fn write_nifti(path: &Path, dimensions: [i16; 3], spacing: [f32; 3]) {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&(HEADER_SIZE as i32).to_le_bytes());
+        header[40..42].copy_from_slice(&3i16.to_le_bytes());
+        for axis in 0..3 {
+            header[42 + 2 * axis..44 + 2 * axis].copy_from_slice(&dimensions[axis].to_le_bytes());
+            header[80 + 4 * axis..84 + 4 * axis].copy_from_slice(&spacing[axis].to_le_bytes());
+        }
+        header[70..72].copy_from_slice(&4i16.to_le_bytes());
+        header[72..74].copy_from_slice(&16i16.to_le_bytes());
+        header[108..112].copy_from_slice(&352f32.to_le_bytes());
+        header[344..348].copy_from_slice(b"n+1\0");
+        header.extend([0; 4]);
+        let voxels: i32 = dimensions.iter().map(|size| *size as i32).product();
+        for voxel in 0..voxels {
+            header.extend((voxel as i16).to_le_bytes());
+        }
+        let mut encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        encoder.write_all(&header).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_read_stack() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("axial.nii.gz");
+        write_nifti(&path, [4, 3, 2], [0.5, 0.5, 3.0]);
+        let stack = Stack::read(&path).unwrap();
+        assert_eq!(stack.header.dimensions, [4, 3, 2]);
+        assert_eq!(stack.to_string(), "axial.nii.gz 4x3x2, 0.50x0.50x3.00 mm");
+        let (_, voxels) = read_volume(&path).unwrap();
+        assert_eq!(voxels.len(), 24);
+        assert_eq!(voxels[23], 23.0);
+
+        std::fs::write(directory.path().join("bad.nii"), [0; 10]).unwrap();
+        assert!(matches!(
+            Stack::read(directory.path().join("bad.nii")),
+            Err(Error::InvalidNifti(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_affine() {
+        let directory = tempfile::tempdir().unwrap();
+        let compressed = directory.path().join("srr.nii.gz");
+        write_nifti(&compressed, [2, 2, 2], [0.5, 0.5, 0.8]);
+        let mut bytes = Vec::new();
+        open(&compressed).unwrap().read_to_end(&mut bytes).unwrap();
+        let path = directory.path().join("srr.nii");
+        let put = |bytes: &mut Vec<u8>, offset: usize, value: &[u8]| {
+            bytes[offset..offset + value.len()].copy_from_slice(value)
+        };
+
+        assert_eq!(
+            Header::read(&compressed).unwrap().affine,
+            [
+                [0.5, 0.0, 0.0, 0.0],
+                [0.0, 0.5, 0.0, 0.0],
+                [0.0, 0.0, 0.800000011920929, 0.0]
+            ]
+        );
+
+        // Rotated by 180 degrees around z, slices reversed.
+        put(&mut bytes, 76, &(-1f32).to_le_bytes());
+        put(&mut bytes, 252, &1i16.to_le_bytes());
+        put(&mut bytes, 264, &1f32.to_le_bytes());
+        put(&mut bytes, 268, &10f32.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let affine = Header::read(&path).unwrap().affine;
+        assert_eq!(affine[0], [-0.5, 0.0, 0.0, 10.0]);
+        assert_eq!(affine[1], [0.0, -0.5, 0.0, 0.0]);
+        assert_eq!(affine[2][2], -0.800000011920929);
+
+        // The sform takes precedence.
+        put(&mut bytes, 254, &1i16.to_le_bytes());
+        for (row, values) in [
+            [0.0f32, 0.5, 0.0, 1.0],
+            [0.0, 0.0, 0.8, 2.0],
+            [0.5, 0.0, 0.0, 3.0],
+        ]
+        .iter()
+        .enumerate()
+        {
+            for (column, value) in values.iter().enumerate() {
+                put(
+                    &mut bytes,
+                    280 + 16 * row + 4 * column,
+                    &value.to_le_bytes(),
+                );
+            }
+        }
+        std::fs::write(&path, &bytes).unwrap();
+        let affine = Header::read(&path).unwrap().affine;
+        assert_eq!(affine[0], [0.0, 0.5, 0.0, 1.0]);
+        assert_eq!(affine[2], [0.5, 0.0, 0.0, 3.0]);
+    }
+
+    #[test]
+    fn test_thumbnail_is_png() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("axial.nii.gz");
+        write_nifti(&path, [4, 3, 2], [1.0, 1.0, 1.0]);
+        let (_, slice) = read_middle_slice(&path).unwrap();
+        assert_eq!(
+            slice,
+            (12..24).map(|voxel| voxel as f32).collect::<Vec<_>>()
+        );
+        let png = thumbnail(&path).unwrap();
+        assert!(png.starts_with(&PNG_SIGNATURE));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), 4);
+        assert_eq!(u32::from_be_bytes(png[20..24].try_into().unwrap()), 3);
+        assert!(png.ends_with(&[0xae, 0x42, 0x60, 0x82]));
+    }
+}
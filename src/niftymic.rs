@@ -1,17 +1,28 @@
-use log::{debug, info};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use std::{
-    ffi::OsStr,
+    collections::HashSet,
     fs,
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
+use tempfile::TempDir;
 use thiserror::Error;
-use ulid::Ulid;
-use walkdir::WalkDir;
 
 use crate::{
-    archive::{Archive, ArchiveError},
-    config::Config,
-    spawn::{spawn_command, DockerWrapper},
+    archive::{Archive, ArchiveError, ExtractLimits, Format, Rejection, Unpacker},
+    cancel::CancellationToken,
+    config::{Config, Deidentification, ExportBackend},
+    container::{self, ContainerRuntime},
+    deidentify::{Deidentifier, Mapping},
+    dicomdir, export,
+    filemgr::{self, FileManager, FileManagerError},
+    inventory::{self, Series},
+    job::{Job, JobState},
+    nifti::{self, Stack},
+    progress::{parse_line, Progress, ProgressCallback},
+    spawn::{spawn_command, CommandFailure, Supervision},
+    study::{self, Study},
 };
 
 #[derive(Debug, Error)]
@@ -29,33 +40,141 @@ pub enum Error {
     #[error("Working directory already exists.")]
     WorkingDirectoryAlreadyExists,
     #[error("Command failed: {0}")]
-    CommandFailed(String),
+    CommandFailed(Box<CommandFailure>),
+    #[error("Failed to start {0}: {1}")]
+    FailedToSpawn(String, #[source] std::io::Error),
+    #[error("{0} timed out after {1:?}")]
+    TimedOut(String, Duration),
+    #[error("Cancelled")]
+    Cancelled,
     #[error("ConfigError: {0}")]
     ConfigError(#[from] config::ConfigError),
     #[error("Failed to open {0}")]
     FailedToOpen(String),
+    #[error(transparent)]
+    FileManager(#[from] FileManagerError),
     #[error("Failed to create working directory {0}")]
     FailedToCreateWorkingDirectory(String),
     #[error("Failed to start bot")]
     FailedToStartBot,
     #[error(transparent)]
     ArchiveError(#[from] ArchiveError),
+    #[error("Invalid job state file: {0}")]
+    InvalidJobState(#[from] serde_json::Error),
+    #[error("Invalid reconstruction option: {0}")]
+    InvalidOption(String),
+    #[error("Unknown preset: {0}")]
+    UnknownPreset(String),
+    #[error("Unknown job: {0}")]
+    UnknownJob(String),
+    #[error("Ambiguous job {0}, matching {}", .1.join(", "))]
+    AmbiguousJob(String, Vec<String>),
+    #[error("Job {0} is still queued or running")]
+    JobActive(String),
+    #[error("Failed to read DICOM file {0}: {1}")]
+    FailedToReadDicom(String, String),
+    #[error("Failed to write DICOM file {0}: {1}")]
+    FailedToWriteDicom(String, String),
+    #[error("No input given")]
+    MissingInput,
+    #[error("No DICOM series found in the input")]
+    NoDicomSeries,
+    #[error("No DICOM series selected for the reconstruction")]
+    NoSeriesSelected,
+    #[error("Invalid series selection: {0}")]
+    InvalidSelection(String),
+    #[error("No NIfTI stack selected for the reconstruction")]
+    NoStackSelected,
+    #[error("Invalid NIfTI image {0}: {1}")]
+    InvalidNifti(String, String),
+    #[error("De-identification failed: {0}")]
+    InvalidDeidentification(String),
+    #[error("Job finished without an output archive")]
+    MissingJobOutput,
 }
 
 pub type Result<T> = std::result::Result<T, self::Error>;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Options {
-    alpha: f32,
-    outlier_rejection: u64,
-    threshold_first: f32,
-    threshold: f32,
-    intensity_correction: u64,
-    isotropic_resolution: f32,
-    two_step_cycles: u64,
+    pub alpha: f32,
+    pub outlier_rejection: u64,
+    pub threshold_first: f32,
+    pub threshold: f32,
+    pub intensity_correction: u64,
+    pub isotropic_resolution: f32,
+    pub two_step_cycles: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha_first: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bias_field_correction: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sigma: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iter_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iter_max_first: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconstruction_type: Option<ReconstructionType>,
+    /// Path relative to the working directory, e.g. `nii/stack1.nii.gz`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconstruction_space: Option<String>,
+    /// Path relative to the working directory, e.g. `nii/stack1.nii.gz`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_stack: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slice_thicknesses: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dilation_radius: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_frame_target: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_robust_registration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search_angle: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verbose: Option<u64>,
 }
 
-impl Options {
-    pub fn default() -> Options {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReconstructionType {
+    TK1L2,
+    TVL2,
+    HuberL2,
+}
+
+impl std::fmt::Display for ReconstructionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ReconstructionType::TK1L2 => "TK1L2",
+            ReconstructionType::TVL2 => "TVL2",
+            ReconstructionType::HuberL2 => "HuberL2",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for ReconstructionType {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "TK1L2" => Ok(ReconstructionType::TK1L2),
+            "TVL2" => Ok(ReconstructionType::TVL2),
+            "HuberL2" => Ok(ReconstructionType::HuberL2),
+            _ => Err(Error::InvalidOption(format!(
+                "Unknown reconstruction type {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
         Options {
             alpha: 0.01,
             outlier_rejection: 1,
@@ -64,13 +183,221 @@ impl Options {
             intensity_correction: 1,
             isotropic_resolution: 0.8,
             two_step_cycles: 3,
+            alpha_first: None,
+            bias_field_correction: None,
+            sigma: None,
+            iter_max: None,
+            iter_max_first: None,
+            iterations: None,
+            reconstruction_type: None,
+            reconstruction_space: None,
+            target_stack: None,
+            slice_thicknesses: None,
+            dilation_radius: None,
+            extra_frame_target: None,
+            use_robust_registration: None,
+            search_angle: None,
+            verbose: None,
+        }
+    }
+}
+
+impl Options {
+    /// Sets a single option from its NiftyMIC flag name, e.g. `isotropic-resolution`.
+    /// The options are left untouched if the new value is out of range.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut options = self.clone();
+        match key.replace('_', "-").as_str() {
+            "alpha" => options.alpha = parse_option(key, value)?,
+            "outlier-rejection" => options.outlier_rejection = parse_option(key, value)?,
+            "threshold-first" => options.threshold_first = parse_option(key, value)?,
+            "threshold" => options.threshold = parse_option(key, value)?,
+            "intensity-correction" => options.intensity_correction = parse_option(key, value)?,
+            "isotropic-resolution" | "resolution" => {
+                options.isotropic_resolution = parse_option(key, value)?
+            }
+            "two-step-cycles" | "cycles" => options.two_step_cycles = parse_option(key, value)?,
+            "alpha-first" => options.alpha_first = Some(parse_option(key, value)?),
+            "bias-field-correction" => {
+                options.bias_field_correction = Some(parse_option(key, value)?)
+            }
+            "sigma" => options.sigma = Some(parse_option(key, value)?),
+            "iter-max" => options.iter_max = Some(parse_option(key, value)?),
+            "iter-max-first" => options.iter_max_first = Some(parse_option(key, value)?),
+            "iterations" => options.iterations = Some(parse_option(key, value)?),
+            "reconstruction-type" => options.reconstruction_type = Some(value.parse()?),
+            "reconstruction-space" => options.reconstruction_space = Some(value.to_string()),
+            "target-stack" => options.target_stack = Some(value.to_string()),
+            "slice-thicknesses" => {
+                options.slice_thicknesses = Some(
+                    value
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|thickness| !thickness.is_empty())
+                        .map(|thickness| parse_option(key, thickness))
+                        .collect::<Result<Vec<f32>>>()?,
+                )
+            }
+            "dilation-radius" => options.dilation_radius = Some(parse_option(key, value)?),
+            "extra-frame-target" => options.extra_frame_target = Some(parse_option(key, value)?),
+            "use-robust-registration" => {
+                options.use_robust_registration = Some(parse_option(key, value)?)
+            }
+            "search-angle" => options.search_angle = Some(parse_option(key, value)?),
+            "verbose" => options.verbose = Some(parse_option(key, value)?),
+            _ => return Err(Error::InvalidOption(format!("Unknown option {}", key))),
+        }
+        options.validate()?;
+        *self = options;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(self.alpha > 0.0 && self.alpha <= 1.0) {
+            return Err(Error::InvalidOption(format!(
+                "alpha must be in ]0, 1], got {}",
+                self.alpha
+            )));
+        }
+        if self.outlier_rejection > 1 {
+            return Err(Error::InvalidOption(format!(
+                "outlier-rejection must be 0 or 1, got {}",
+                self.outlier_rejection
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.threshold_first) {
+            return Err(Error::InvalidOption(format!(
+                "threshold-first must be in [0, 1], got {}",
+                self.threshold_first
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.threshold) {
+            return Err(Error::InvalidOption(format!(
+                "threshold must be in [0, 1], got {}",
+                self.threshold
+            )));
+        }
+        if self.intensity_correction > 1 {
+            return Err(Error::InvalidOption(format!(
+                "intensity-correction must be 0 or 1, got {}",
+                self.intensity_correction
+            )));
+        }
+        if !(self.isotropic_resolution > 0.0 && self.isotropic_resolution <= 5.0) {
+            return Err(Error::InvalidOption(format!(
+                "isotropic-resolution must be in ]0, 5], got {}",
+                self.isotropic_resolution
+            )));
+        }
+        if self.two_step_cycles > 10 {
+            return Err(Error::InvalidOption(format!(
+                "two-step-cycles must be in [0, 10], got {}",
+                self.two_step_cycles
+            )));
+        }
+        if let Some(alpha_first) = self.alpha_first {
+            if !(alpha_first > 0.0 && alpha_first <= 1.0) {
+                return Err(Error::InvalidOption(format!(
+                    "alpha-first must be in ]0, 1], got {}",
+                    alpha_first
+                )));
+            }
+        }
+        for (name, flag) in [
+            ("bias-field-correction", self.bias_field_correction),
+            ("use-robust-registration", self.use_robust_registration),
+            ("verbose", self.verbose),
+        ] {
+            if let Some(flag) = flag.filter(|flag| *flag > 1) {
+                return Err(Error::InvalidOption(format!(
+                    "{} must be 0 or 1, got {}",
+                    name, flag
+                )));
+            }
+        }
+        if let Some(sigma) = self.sigma {
+            if sigma <= 0.0 {
+                return Err(Error::InvalidOption(format!(
+                    "sigma must be positive, got {}",
+                    sigma
+                )));
+            }
+        }
+        for (name, iterations) in [
+            ("iter-max", self.iter_max),
+            ("iter-max-first", self.iter_max_first),
+            ("iterations", self.iterations),
+        ] {
+            if let Some(iterations) = iterations.filter(|n| !(1..=100).contains(n)) {
+                return Err(Error::InvalidOption(format!(
+                    "{} must be in [1, 100], got {}",
+                    name, iterations
+                )));
+            }
+        }
+        if let Some(slice_thicknesses) = &self.slice_thicknesses {
+            if slice_thicknesses.iter().any(|thickness| *thickness <= 0.0) {
+                return Err(Error::InvalidOption(
+                    "slice-thicknesses must all be positive".to_string(),
+                ));
+            }
+        }
+        if let Some(dilation_radius) = self.dilation_radius {
+            if dilation_radius > 20 {
+                return Err(Error::InvalidOption(format!(
+                    "dilation-radius must be in [0, 20], got {}",
+                    dilation_radius
+                )));
+            }
+        }
+        if let Some(extra_frame_target) = self.extra_frame_target {
+            if extra_frame_target < 0.0 {
+                return Err(Error::InvalidOption(format!(
+                    "extra-frame-target must not be negative, got {}",
+                    extra_frame_target
+                )));
+            }
+        }
+        if let Some(search_angle) = self.search_angle {
+            if search_angle > 180 {
+                return Err(Error::InvalidOption(format!(
+                    "search-angle must be in [0, 180], got {}",
+                    search_angle
+                )));
+            }
+        }
+        for (name, path) in [
+            ("reconstruction-space", &self.reconstruction_space),
+            ("target-stack", &self.target_stack),
+        ] {
+            if let Some(path) = path {
+                let path = Path::new(path);
+                if path.is_absolute() || path.components().any(|c| c.as_os_str() == "..") {
+                    return Err(Error::InvalidOption(format!(
+                        "{} must be relative to the working directory, got {}",
+                        name,
+                        path.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy with `target_stack` and `reconstruction_space` rooted at `base_directory`.
+    pub fn with_base_directory(&self, base_directory: &str) -> Options {
+        let rebase = |path: &Option<String>| {
+            path.as_ref()
+                .map(|path| Path::new(base_directory).join(path).display().to_string())
+        };
+        Options {
+            reconstruction_space: rebase(&self.reconstruction_space),
+            target_stack: rebase(&self.target_stack),
+            ..self.clone()
         }
     }
 
     pub fn to_args(&self) -> Vec<String> {
-        let mut args = Vec::new();
-        args.push("--alpha".to_string());
-        args.push(self.alpha.to_string());
+        let mut args = vec!["--alpha".to_string(), self.alpha.to_string()];
         args.push("--outlier-rejection".to_string());
         args.push(self.outlier_rejection.to_string());
         args.push("--threshold-first".to_string());
@@ -83,204 +410,500 @@ impl Options {
         args.push(self.isotropic_resolution.to_string());
         args.push("--two-step-cycles".to_string());
         args.push(self.two_step_cycles.to_string());
+        push_optional(&mut args, "--alpha-first", &self.alpha_first);
+        push_optional(
+            &mut args,
+            "--bias-field-correction",
+            &self.bias_field_correction,
+        );
+        push_optional(&mut args, "--sigma", &self.sigma);
+        push_optional(&mut args, "--iter-max", &self.iter_max);
+        push_optional(&mut args, "--iter-max-first", &self.iter_max_first);
+        push_optional(&mut args, "--iterations", &self.iterations);
+        push_optional(
+            &mut args,
+            "--reconstruction-type",
+            &self.reconstruction_type,
+        );
+        push_optional(
+            &mut args,
+            "--reconstruction-space",
+            &self.reconstruction_space,
+        );
+        push_optional(&mut args, "--target-stack", &self.target_stack);
+        if let Some(slice_thicknesses) = &self.slice_thicknesses {
+            args.push("--slice-thicknesses".to_string());
+            args.extend(
+                slice_thicknesses
+                    .iter()
+                    .map(|thickness| thickness.to_string()),
+            );
+        }
+        push_optional(&mut args, "--dilation-radius", &self.dilation_radius);
+        push_optional(&mut args, "--extra-frame-target", &self.extra_frame_target);
+        push_optional(
+            &mut args,
+            "--use-robust-registration",
+            &self.use_robust_registration,
+        );
+        push_optional(&mut args, "--search-angle", &self.search_angle);
         args.push("--verbose".to_string());
-        args.push("1".to_string());
+        args.push(self.verbose.unwrap_or(1).to_string());
         args
     }
 }
 
+fn push_optional<T: std::fmt::Display>(args: &mut Vec<String>, flag: &str, value: &Option<T>) {
+    if let Some(value) = value {
+        args.push(flag.to_string());
+        args.push(value.to_string());
+    }
+}
+
+fn parse_option<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidOption(format!("{} is not a valid value for {}", value, key)))
+}
+
 pub struct WorkingDirectory {
     pub directory: String,
     pub path: PathBuf,
-    pub archive: PathBuf,
-    pub nii: PathBuf,
-    pub masks: PathBuf,
-    pub output_nii: PathBuf,
-    pub output_dicom: PathBuf,
+    pub files: FileManager,
 }
 
 impl WorkingDirectory {
     pub fn new(path: &str) -> WorkingDirectory {
-        let path = Path::new(path);
-        let directory_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        WorkingDirectory::from_file_manager(FileManager::new(path))
+    }
 
+    fn from_file_manager(files: FileManager) -> WorkingDirectory {
         WorkingDirectory {
-            path: path.to_path_buf(),
-            directory: directory_name,
-            archive: path.join("archive"),
-            nii: path.join("nii"),
-            masks: path.join("masks"),
-            output_nii: path.join("output_nii"),
-            output_dicom: path.join("output_dicom"),
+            directory: files.name(),
+            path: files.root().as_path().to_path_buf(),
+            files,
         }
     }
 
-    pub fn from_archive(archive_path: &str, base_directory: &str) -> Result<WorkingDirectory> {
+    pub fn from_archive(
+        archive_path: &str,
+        base_directory: &str,
+        limits: &ExtractLimits,
+        deidentification: &Deidentification,
+    ) -> Result<WorkingDirectory> {
+        let working_directory = Self::from_inputs(
+            &[archive_path.to_string()],
+            base_directory,
+            limits,
+            deidentification,
+        )?;
+        fs::remove_file(archive_path)?;
+        Ok(working_directory)
+    }
+
+    /// Creates a working directory from archives, DICOM directories such as a
+    /// CD export with a DICOMDIR, or loose DICOM files. Inputs are left in place,
+    /// their copies are de-identified when enabled.
+    pub fn from_inputs(
+        inputs: &[String],
+        base_directory: &str,
+        limits: &ExtractLimits,
+        deidentification: &Deidentification,
+    ) -> Result<WorkingDirectory> {
+        let first = inputs.first().ok_or(Error::MissingInput)?;
         debug!(
-            "Creating working directory from {} {}",
-            archive_path, base_directory
+            "Creating working directory from {} in {}",
+            inputs.join(", "),
+            base_directory
         );
-        let base_directory = Path::new(base_directory);
-        let directory_name = Self::generate_working_directory_name(archive_path);
-        let path = base_directory.join(directory_name);
-        let working_directory = WorkingDirectory::new(&path.to_str().unwrap());
-
-        fs::create_dir(&working_directory.path).or_else(|error| {
-            Err(Error::FailedToCreateWorkingDirectory(format!(
-                "{} {}",
-                path.display().to_string(),
-                error.to_string()
-            )))
-        })?;
-        fs::create_dir(&working_directory.archive)?;
-        fs::create_dir(&working_directory.nii)?;
-        fs::create_dir(&working_directory.masks)?;
-        fs::create_dir(&working_directory.output_nii)?;
-        fs::create_dir(&working_directory.output_dicom)?;
-        Archive::new(&archive_path).extract(&working_directory.archive)?;
-        fs::remove_file(archive_path)?;
+        let input_file_stem = Path::new(first)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().trim_end_matches(".tar").to_string())
+            .unwrap_or_default();
+        // The input name may hold the patient name.
+        let prefix = match deidentification.enable {
+            true => "job",
+            false => &input_file_stem,
+        };
+        let files = FileManager::generate(base_directory, prefix);
+        files
+            .create()
+            .map_err(|error| Error::FailedToCreateWorkingDirectory(error.to_string()))?;
+        let staged = Self::stage_inputs(inputs, files.archive().as_path(), limits)
+            .and_then(|()| Self::stage_nifti_stacks(&files, deidentification.enable))
+            .and_then(|()| match deidentification.enable {
+                true => deidentify(&files, deidentification, files.archive().as_path()),
+                false => Ok(()),
+            });
+        if let Err(error) = staged {
+            fs::remove_dir_all(files.root().as_path())?;
+            return Err(error);
+        }
+        let working_directory = WorkingDirectory::from_file_manager(files);
+        working_directory.save_job(&Job::new())?;
         Ok(working_directory)
     }
 
-    pub fn generate_working_directory_name(input_file_path: &str) -> String {
-        let input_file_stem = Path::new(input_file_path).file_stem().unwrap();
-        format!(
-            "{}-{}",
-            input_file_stem.to_str().unwrap(),
-            Ulid::new().to_string()
-        )
+    fn stage_inputs(inputs: &[String], destination: &Path, limits: &ExtractLimits) -> Result<()> {
+        let mut unpacker = Unpacker::new(destination, limits);
+        for (index, input) in inputs.iter().enumerate() {
+            let path = Path::new(input);
+            // Separate folders keep same-named files of different inputs apart.
+            let prefix = match inputs.len() {
+                1 => PathBuf::new(),
+                _ => PathBuf::from(format!("{:03}", index + 1)),
+            };
+            if path.is_dir() {
+                unpacker.add_directory(path, &prefix)?;
+                continue;
+            }
+            // Compressed NIfTI images share the gzip magic with tar.gz archives.
+            if filemgr::is_nifti(path) {
+                unpacker.add_file(path)?;
+                continue;
+            }
+            match Format::detect(path) {
+                Ok(_) => unpacker.add_archive(&Archive::new(path), &prefix)?,
+                Err(ArchiveError::Rejected(Rejection::UnknownFormat)) => unpacker.add_file(path)?,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        dicomdir::prune_unreferenced(destination)?;
+        Ok(())
+    }
+
+    /// Moves NIfTI stacks found among the inputs to the NIfTI directory, where the
+    /// conversion would have written them, and drops the remaining inputs. Inputs
+    /// holding DICOM are converted instead, their NIfTI files are dropped. Stacks
+    /// are renamed `stack-N` when `anonymous`, their names may hold the patient name.
+    fn stage_nifti_stacks(files: &FileManager, anonymous: bool) -> Result<()> {
+        let stacks = files.archive().nifti_files();
+        if stacks.is_empty() {
+            return Ok(());
+        }
+        if !inventory::scan(files.archive().as_path())?.is_empty() {
+            warn!(
+                "Ignoring {} NIfTI files next to the DICOM input",
+                stacks.len()
+            );
+            for stack in &stacks {
+                fs::remove_file(stack)?;
+            }
+            return Ok(());
+        }
+        for (index, stack) in stacks.iter().enumerate() {
+            let name = if anonymous {
+                let extension = match stack.to_lowercase().ends_with(".gz") {
+                    true => "nii.gz",
+                    false => "nii",
+                };
+                format!("stack-{}.{}", index + 1, extension)
+            } else {
+                // Flattened relative path, unique even for same-named stacks of different inputs.
+                let relative = Path::new(stack)
+                    .strip_prefix(files.archive().as_path())
+                    .unwrap_or(Path::new(stack));
+                relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("_")
+            };
+            fs::rename(stack, files.nii().join(name))?;
+        }
+        let ignored = files.archive().list_files();
+        if !ignored.is_empty() {
+            info!(
+                "Ignoring {} non-NIfTI input files next to the NIfTI stacks",
+                ignored.len()
+            );
+        }
+        files.archive().clean()?;
+        info!("Received {} NIfTI stacks", stacks.len());
+        Ok(())
+    }
+
+    /// Lists every working directory holding a job state file under `base_directory`.
+    pub fn list(base_directory: &str) -> Result<Vec<WorkingDirectory>> {
+        let mut working_directories = Vec::new();
+        for entry in fs::read_dir(base_directory)? {
+            let path = entry?.path();
+            if FileManager::is_job_directory(&path) {
+                working_directories
+                    .push(WorkingDirectory::from_file_manager(FileManager::new(path)));
+            }
+        }
+        working_directories.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(working_directories)
+    }
+
+    /// Finds a working directory under `base_directory` by its name or its ULID suffix.
+    pub fn find(base_directory: &str, id: &str) -> Result<WorkingDirectory> {
+        let id = id.to_lowercase();
+        let mut matches: Vec<WorkingDirectory> = Self::list(base_directory)?
+            .into_iter()
+            .filter(|working_directory| {
+                let name = working_directory.directory.to_lowercase();
+                name == id || name.rsplit_once('-').is_some_and(|(_, ulid)| ulid == id)
+            })
+            .collect();
+        match matches.len() {
+            0 => Err(Error::UnknownJob(id)),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                let mut names: Vec<String> = matches
+                    .into_iter()
+                    .map(|working_directory| working_directory.directory)
+                    .collect();
+                names.sort();
+                Err(Error::AmbiguousJob(id, names))
+            }
+        }
+    }
+
+    pub fn load_job(&self) -> Result<Job> {
+        Job::load(self.files.job_file())
+    }
+
+    pub fn save_job(&self, job: &Job) -> Result<()> {
+        job.save(self.files.job_file())
+    }
+
+    pub fn update_job<F: FnOnce(&mut Job)>(&self, update: F) -> Result<Job> {
+        let mut job = self.load_job()?;
+        update(&mut job);
+        self.save_job(&job)?;
+        Ok(job)
     }
 
     pub fn get_nifti_filename(&self) -> String {
-        format!("{}.nii.gz", self.directory)
+        self.files.nifti_filename()
     }
 
     pub fn get_dicom_filename(&self) -> String {
-        format!("{}.zip", self.directory)
+        self.files.dicom_filename()
     }
 
-    pub fn get_relative_mask_directory(&self, relative_to: &str) -> String {
-        self.replace_base_directory(&self.masks.display().to_string(), relative_to)
+    pub fn get_relative_mask_directory(&self, relative_to: &str) -> Result<String> {
+        Ok(self
+            .files
+            .rebase(self.files.masks().as_path(), relative_to)?)
     }
 
-    pub fn get_relative_nifti_output(&self, relative_to: &str) -> String {
-        self.replace_base_directory(
-            &self
-                .output_nii
-                .join(self.get_nifti_filename())
-                .display()
-                .to_string(),
-            relative_to,
-        )
+    pub fn get_relative_nifti_output(&self, relative_to: &str) -> Result<String> {
+        Ok(self.files.rebase(self.files.output_nifti(), relative_to)?)
     }
 
-    pub fn get_absolute_nifti_output(&self) -> String {
-        self.absolute(&self.output_nii)
+    pub fn get_absolute_nifti_output(&self) -> Result<String> {
+        Ok(self
+            .files
+            .output_nii()
+            .absolute()?
             .join(self.get_nifti_filename())
             .display()
-            .to_string()
+            .to_string())
     }
 
-    pub fn get_absolute_dicom_output(&self) -> String {
-        self.absolute(&self.path)
+    pub fn get_absolute_dicom_output(&self) -> Result<String> {
+        Ok(self
+            .files
+            .root()
+            .absolute()?
             .join(self.get_dicom_filename())
             .display()
-            .to_string()
+            .to_string())
     }
 
-    pub fn get_absolute_dicom_output_directory(&self) -> String {
-        self.absolute(&self.output_dicom).display().to_string()
+    pub fn get_absolute_dicom_output_directory(&self) -> Result<String> {
+        Ok(self.files.output_dicom().absolute()?.display().to_string())
     }
 
-    pub fn get_relative_nifti_images(&self, relative_to: &str) -> Vec<String> {
-        self.switch_working_directory(
-            self.search_files_by_extension(&self.nii.display().to_string(), "nii"),
-            relative_to,
-        )
+    /// Stacks selected on the job, relative to `relative_to`.
+    pub fn get_relative_nifti_images(&self, relative_to: &str) -> Result<Vec<String>> {
+        let job = self.load_job()?;
+        let images: Vec<String> = self
+            .files
+            .nii()
+            .nifti_files()
+            .into_iter()
+            .filter(|image| job.is_stack_selected(&file_name(image)))
+            .collect();
+        if images.is_empty() {
+            return Err(Error::NoStackSelected);
+        }
+        self.rebase_all(images, relative_to)
     }
 
-    pub fn get_relative_mask_images(&self, relative_to: &str) -> Vec<String> {
-        self.switch_working_directory(
-            self.search_files_by_extension(&self.masks.display().to_string(), "gz"),
-            relative_to,
-        )
+    /// Every NIfTI stack available for the reconstruction, selected or not.
+    pub fn stacks(&self) -> Result<Vec<Stack>> {
+        self.files
+            .nii()
+            .nifti_files()
+            .iter()
+            .map(Stack::read)
+            .collect()
+    }
+
+    /// Selects exactly the stacks at the 1-based positions `picks` of `stacks`.
+    pub fn pick_stacks(&self, picks: &[usize]) -> Result<Job> {
+        let names: Vec<String> = self
+            .files
+            .nii()
+            .nifti_files()
+            .iter()
+            .map(|image| file_name(image))
+            .collect();
+        if let Some(pick) = picks
+            .iter()
+            .find(|pick| **pick == 0 || **pick > names.len())
+        {
+            return Err(Error::InvalidSelection(format!(
+                "there is no stack {}, pick between 1 and {}",
+                pick,
+                names.len()
+            )));
+        }
+        if picks.is_empty() {
+            return Err(Error::InvalidSelection(
+                "pick at least one stack".to_string(),
+            ));
+        }
+        self.update_job(|job| {
+            job.stacks = Some(
+                names
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| picks.contains(&(index + 1)))
+                    .map(|(_, name)| name.clone())
+                    .collect(),
+            )
+        })
+    }
+
+    pub fn get_relative_mask_images(&self, relative_to: &str) -> Result<Vec<String>> {
+        self.rebase_all(self.files.masks().files_with_extension("gz"), relative_to)
     }
 
     pub fn get_final_dicom_images(&self) -> Vec<String> {
-        self.search_files_by_extension(&self.output_dicom.display().to_string(), "dcm")
+        self.files.output_dicom().files_with_extension("dcm")
     }
 
-    pub fn absolute_path(&self) -> String {
-        self.absolute(&self.path).display().to_string()
+    pub fn absolute_path(&self) -> Result<String> {
+        Ok(self.files.root().absolute()?.display().to_string())
     }
 
     pub fn clean_output_dicom(&self) -> Result<()> {
-        self.clean(&self.output_dicom)
+        Ok(self.files.output_dicom().clean()?)
     }
 
     pub fn clean_dicom(&self) -> Result<()> {
-        self.clean(&self.archive)
+        Ok(self.files.archive().clean()?)
     }
 
-    fn clean(&self, path: &PathBuf) -> Result<()> {
-        for entry in WalkDir::new(path)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.path().is_file())
-        {
-            fs::remove_file(entry.path())?;
-        }
-        Ok(())
+    /// Whether the inputs were NIfTI stacks, in which case there is nothing to convert.
+    pub fn has_nifti_input(&self) -> bool {
+        self.files.archive().list_files().is_empty() && !self.files.nii().nifti_files().is_empty()
     }
 
-    fn absolute(&self, path: &PathBuf) -> PathBuf {
-        match std::fs::canonicalize(path) {
-            Ok(path) => path.clone(),
-            Err(error) => panic!(
-                "Failed to canonicalize {}: {}",
-                path.display().to_string(),
-                error.to_string()
-            ),
-        }
+    /// DICOM series of the staged input.
+    pub fn inventory(&self) -> Result<Vec<Series>> {
+        inventory::scan(self.files.archive().as_path())
     }
 
-    fn replace_base_directory(&self, path: &str, replacement: &str) -> String {
-        let remove_prefix = Path::new(path)
-            .strip_prefix(&self.path.display().to_string())
-            .unwrap();
-        let replace_prefix = Path::new(replacement).join(remove_prefix);
-        replace_prefix.display().to_string()
+    /// Patient and study context of the first series selected in `series`.
+    pub fn study(&self, series: &[Series]) -> Result<Option<Study>> {
+        if series.is_empty() {
+            return Ok(None);
+        }
+        let selected: HashSet<&str> = series
+            .iter()
+            .filter(|series| series.selected)
+            .map(|series| series.series_instance_uid.as_str())
+            .collect();
+        let mut inventory = self.inventory()?;
+        for series in &mut inventory {
+            series.selected = selected.contains(series.series_instance_uid.as_str());
+        }
+        Study::from_series(&inventory)
     }
 
-    fn search_files_by_extension(&self, target_directory: &str, extension: &str) -> Vec<String> {
-        let mut files = Vec::new();
-
-        for entry in WalkDir::new(target_directory)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| Some(OsStr::new(extension)) == e.path().extension())
-        {
-            files.push(entry.path().display().to_string());
+    /// Links the files of the series selected in `series` into a directory of
+    /// their own, leaving the input intact should the selection change. Jobs
+    /// inspected before series were recorded convert every file.
+    pub fn stage_selected_series(&self, series: &[Series]) -> Result<Option<TempDir>> {
+        if series.is_empty() {
+            return Ok(None);
         }
-        files.sort();
-        for file in &files {
-            debug!("Found {} in {}", file, target_directory);
+        let selected: HashSet<&str> = series
+            .iter()
+            .filter(|series| series.selected)
+            .map(|series| series.series_instance_uid.as_str())
+            .collect();
+        if selected.is_empty() {
+            return Err(Error::NoSeriesSelected);
         }
-        files
+        let staging = tempfile::Builder::new()
+            .prefix("selected-")
+            .tempdir_in(self.files.root().as_path())?;
+        for (index, series) in self.inventory()?.iter().enumerate() {
+            if !selected.contains(series.series_instance_uid.as_str()) {
+                info!("Skipping series {}", series);
+                continue;
+            }
+            for (number, file) in series.files.iter().enumerate() {
+                let link = staging
+                    .path()
+                    .join(format!("{:03}-{:05}.dcm", index + 1, number + 1));
+                if fs::hard_link(file, &link).is_err() {
+                    fs::copy(file, &link)?;
+                }
+            }
+        }
+        Ok(Some(staging))
+    }
+
+    pub fn clean_nii(&self) -> Result<()> {
+        Ok(self.files.nii().clean()?)
+    }
+
+    pub fn clean_masks(&self) -> Result<()> {
+        Ok(self.files.masks().clean()?)
     }
 
-    fn switch_working_directory(&self, files: Vec<String>, working_directory: &str) -> Vec<String> {
+    fn rebase_all(&self, files: Vec<String>, relative_to: &str) -> Result<Vec<String>> {
         files
-            .into_iter()
-            .map(|e| self.replace_base_directory(&e, working_directory))
+            .iter()
+            .map(|file| Ok(self.files.rebase(file, relative_to)?))
             .collect()
     }
 }
 
+/// De-identifies the DICOM files below `directory`, keeping the UIDs and
+/// pseudonyms consistent with the mapping file of the job.
+fn deidentify(files: &FileManager, settings: &Deidentification, directory: &Path) -> Result<()> {
+    let name = files.name();
+    let prefix = name
+        .rsplit_once('-')
+        .map_or(name.as_str(), |(_, ulid)| ulid);
+    let mapping = Mapping::load(files.deidentification_file())?;
+    let mut deidentifier = Deidentifier::new(settings, prefix, mapping)?;
+    deidentifier.deidentify_directory(directory)?;
+    deidentifier.mapping().save(files.deidentification_file())
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
 pub struct NiftyMic {
     working_directory: WorkingDirectory,
-    docker_wrapper: DockerWrapper,
+    container: Box<dyn ContainerRuntime>,
     config: Config,
+    progress: Option<ProgressCallback>,
+    cancel: CancellationToken,
 }
 
 impl NiftyMic {
@@ -289,83 +912,265 @@ impl NiftyMic {
             working_directory: WorkingDirectory::from_archive(
                 archive_path,
                 &config.output.base_directory,
+                &config.archive,
+                &config.deidentification,
             )?,
-            docker_wrapper: DockerWrapper::from_config(config),
+            container: container::from_config(config),
             config: config.clone(),
+            progress: None,
+            cancel: CancellationToken::new(),
+        })
+    }
+
+    pub fn from_inputs(inputs: &[String], config: &Config) -> Result<NiftyMic> {
+        Ok(NiftyMic {
+            working_directory: WorkingDirectory::from_inputs(
+                inputs,
+                &config.output.base_directory,
+                &config.archive,
+                &config.deidentification,
+            )?,
+            container: container::from_config(config),
+            config: config.clone(),
+            progress: None,
+            cancel: CancellationToken::new(),
         })
     }
 
     pub fn from_working_directory(working_directory: &str, config: &Config) -> Result<NiftyMic> {
         Ok(NiftyMic {
             working_directory: WorkingDirectory::new(working_directory),
-            docker_wrapper: DockerWrapper::from_config(config),
+            container: container::from_config(config),
             config: config.clone(),
+            progress: None,
+            cancel: CancellationToken::new(),
         })
     }
 
+    /// Registers a token that stops the running step and the remaining ones once cancelled.
+    pub fn set_cancellation_token(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    fn supervision(&self, timeout: Option<u64>) -> Supervision {
+        Supervision::new(timeout.map(Duration::from_secs), &self.cancel)
+    }
+
+    /// Registers a callback receiving progress events parsed from the pipeline output.
+    pub fn set_progress_callback(&mut self, callback: ProgressCallback) {
+        self.progress = Some(callback);
+    }
+
+    fn report(&self, progress: Progress) {
+        if let Some(callback) = &self.progress {
+            callback(progress);
+        }
+    }
+
+    fn report_line(&self, line: &str) {
+        if let Some(progress) = parse_line(line) {
+            self.report(progress);
+        }
+    }
+
+    pub fn working_directory(&self) -> &WorkingDirectory {
+        &self.working_directory
+    }
+
+    /// Runs the pipeline from the first incomplete step recorded in the job state file.
+    pub fn run(&self, options: &Options) -> Result<String> {
+        self.run_until(options, JobState::Exported)?
+            .output
+            .ok_or(Error::MissingJobOutput)
+    }
+
+    /// Like `run`, stopping once `last` is completed.
+    pub fn run_until(&self, options: &Options, last: JobState) -> Result<Job> {
+        options.validate()?;
+        let mut job = self
+            .working_directory
+            .update_job(|job| job.options = Some(options.clone()))?;
+        if job.is_failed() {
+            info!(
+                "Resuming failed job {} after {:?}",
+                self.working_directory.directory,
+                job.resume_state()
+            );
+        }
+        while let Some(next) = job.resume_state().next().filter(|next| *next <= last) {
+            self.report(Progress::Step(next));
+            let started = Instant::now();
+            let result = if self.cancel.is_cancelled() {
+                Err(Error::Cancelled)
+            } else {
+                self.run_step(next, options, &mut job)
+            };
+            match result {
+                Ok(()) => {
+                    job.durations.insert(next, started.elapsed().as_secs_f64());
+                    job.advance(next);
+                }
+                Err(error) => {
+                    job.fail(&error.to_string());
+                    self.working_directory.save_job(&job)?;
+                    return Err(error);
+                }
+            }
+            self.working_directory.save_job(&job)?;
+        }
+        Ok(job)
+    }
+
+    fn run_step(&self, step: JobState, options: &Options, job: &mut Job) -> Result<()> {
+        match step {
+            JobState::Inspected => {
+                if !self.working_directory.has_nifti_input() {
+                    job.series = self.inspect_input()?;
+                }
+                Ok(())
+            }
+            JobState::Converted => {
+                // The series may have been picked again since the inspection.
+                job.study = self.working_directory.study(&job.series)?;
+                self.convert_dicom_to_nifti(&job.series)
+            }
+            JobState::Masked => self.generate_masks_from_nifti(),
+            JobState::Reconstructed => self.reconstruct(options),
+            JobState::Exported => self
+                .convert_nifti_to_dicom(job.study.as_ref())
+                .map(|output| {
+                    job.output = Some(output);
+                }),
+            JobState::Received | JobState::Failed => unreachable!(),
+        }
+    }
+
+    /// Job directory as seen by the NiftyMIC tools: the container mount point,
+    /// or the host path when running natively.
+    fn tool_directory(&self) -> Result<String> {
+        match self.container.mount_point() {
+            Some(mount_point) => Ok(mount_point.to_string()),
+            None => self.working_directory.absolute_path(),
+        }
+    }
+
     pub fn generate_masks_from_nifti(&self) -> Result<()> {
+        self.working_directory.clean_masks()?;
+        let tool_directory = self.tool_directory()?;
         let mut args = Vec::new();
         args.push("--filenames".to_string());
         args.append(
             &mut self
                 .working_directory
-                .get_relative_nifti_images(&self.docker_wrapper.working_directory),
+                .get_relative_nifti_images(&tool_directory)?,
         );
         args.push("--dir-output".to_string());
         args.push(
             self.working_directory
-                .get_relative_mask_directory(&self.docker_wrapper.working_directory),
+                .get_relative_mask_directory(&tool_directory)?,
         );
         info!("Generating masks from NIfTI images");
-        self.docker_wrapper.run(
+        self.container.run(
             "niftymic_segment_fetal_brains",
             &args,
-            &self.working_directory.absolute_path(),
+            &self.working_directory.absolute_path()?,
+            &self.supervision(self.config.timeouts.masks),
+            &|line| self.report_line(line),
         )?;
         info!("Successfully generated masks from NifTI images");
         Ok(())
     }
 
-    pub fn reconstruct(&self, options: Options) -> Result<()> {
+    pub fn reconstruct(&self, options: &Options) -> Result<()> {
+        options.validate()?;
+        let tool_directory = self.tool_directory()?;
         let mut args = Vec::new();
         args.push("--filenames".to_string());
         args.append(
             &mut self
                 .working_directory
-                .get_relative_nifti_images(&self.docker_wrapper.working_directory),
+                .get_relative_nifti_images(&tool_directory)?,
         );
         args.push("--filenames-masks".to_string());
         args.append(
             &mut self
                 .working_directory
-                .get_relative_mask_images(&self.docker_wrapper.working_directory),
+                .get_relative_mask_images(&tool_directory)?,
         );
-        args.append(&mut options.to_args());
+        args.append(&mut options.with_base_directory(&tool_directory).to_args());
         args.push("--output".to_string());
         args.push(
             self.working_directory
-                .get_relative_nifti_output(&self.docker_wrapper.working_directory),
+                .get_relative_nifti_output(&tool_directory)?,
         );
         info!("Starting reconstruction of the volume");
-        self.docker_wrapper.run(
+        self.container.run(
             "niftymic_reconstruct_volume",
             &args,
-            &self.working_directory.absolute_path(),
+            &self.working_directory.absolute_path()?,
+            &self.supervision(self.config.timeouts.reconstruct),
+            &|line| self.report_line(line),
         )?;
         info!("Successfully reconstruct volume");
         Ok(())
     }
 
-    pub fn convert_dicom_to_nifti(&self) -> Result<()> {
+    /// Lists the DICOM series of the input and selects them according to the
+    /// configured rules, failing early when there is none.
+    pub fn inspect_input(&self) -> Result<Vec<Series>> {
+        let mut series = self.working_directory.inventory()?;
+        if series.is_empty() {
+            return Err(Error::NoDicomSeries);
+        }
+        inventory::select(&mut series, &self.config.selection)?;
+        for series in &series {
+            info!(
+                "Found series {} ({})",
+                series,
+                if series.selected {
+                    "selected"
+                } else {
+                    "skipped"
+                }
+            );
+        }
+        Ok(series)
+    }
+
+    /// Converts the DICOM series selected in `series` to NIfTI, every series
+    /// when `series` is empty.
+    pub fn convert_dicom_to_nifti(&self, series: &[Series]) -> Result<()> {
+        if self.working_directory.has_nifti_input() {
+            info!("Inputs are NIfTI stacks, skipping DICOM conversion");
+            return Ok(());
+        }
         info!("Start converting DICOM to NIfTI");
+        self.working_directory.clean_nii()?;
+        let staging = self.working_directory.stage_selected_series(series)?;
+        let input = match &staging {
+            Some(staging) => staging.path().to_path_buf(),
+            None => self
+                .working_directory
+                .files
+                .archive()
+                .as_path()
+                .to_path_buf(),
+        };
         spawn_command(
             &self.config.executables.dcm2niix,
-            &vec![
+            &[
                 "-o".to_string(),
-                self.working_directory.nii.display().to_string(),
-                self.working_directory.archive.display().to_string(),
+                self.working_directory
+                    .files
+                    .nii()
+                    .as_path()
+                    .display()
+                    .to_string(),
+                input.display().to_string(),
             ],
             None,
+            &self.supervision(self.config.timeouts.convert),
+            &|line| self.report_line(line),
         )?;
         info!("Successfully convert input archive to nifti files");
         info!("Removing DICOM");
@@ -373,31 +1178,307 @@ impl NiftyMic {
         Ok(())
     }
 
-    pub fn convert_nifti_to_dicom(&self) -> Result<String> {
+    /// Converts the reconstruction to a DICOM series of `study`, the input
+    /// study, and archives it.
+    pub fn convert_nifti_to_dicom(&self, study: Option<&Study>) -> Result<String> {
         info!("Start converting NIfTI to DICOM");
-        debug!(
-            "Set working_directory to: {}",
-            self.working_directory.get_absolute_dicom_output_directory()
-        );
         self.working_directory.clean_output_dicom()?;
+        let native = match self.config.export.backend {
+            ExportBackend::Native => match export::write_series(
+                self.working_directory.get_absolute_nifti_output()?,
+                self.working_directory.files.output_dicom().as_path(),
+                self.config.export.multiframe,
+            ) {
+                Ok(_) => true,
+                Err(error) => {
+                    warn!(
+                        "Native DICOM export failed, falling back to medcon: {}",
+                        error
+                    );
+                    self.working_directory.clean_output_dicom()?;
+                    false
+                }
+            },
+            ExportBackend::Medcon => false,
+        };
+        // The native series is positioned from the NIfTI affine already.
+        let spacing = match native {
+            true => None,
+            false => {
+                self.convert_with_medcon()?;
+                let header =
+                    nifti::Header::read(self.working_directory.get_absolute_nifti_output()?)?;
+                Some(header.spacing[2] as f64)
+            }
+        };
+        info!("Successfully convert NIfTI to DICOM");
+        study::rewrite_series(
+            &self.working_directory.get_final_dicom_images(),
+            study,
+            spacing,
+        )?;
+        if self.config.deidentification.enable {
+            let files = &self.working_directory.files;
+            deidentify(
+                files,
+                &self.config.deidentification,
+                files.output_dicom().as_path(),
+            )?;
+        }
+        info!(
+            "Creating output archive {}",
+            self.working_directory.get_dicom_filename()
+        );
+        let output = self.working_directory.get_absolute_dicom_output()?;
+        Archive::new(&output).create(
+            self.working_directory.files.output_dicom().as_path(),
+            self.working_directory.get_final_dicom_images().as_slice(),
+            self.config.output.compression_level,
+        )?;
+        Ok(output)
+    }
+
+    /// Converts the reconstruction with `medcon`, one file per slice.
+    fn convert_with_medcon(&self) -> Result<()> {
+        let output_directory = self
+            .working_directory
+            .get_absolute_dicom_output_directory()?;
+        debug!("Set working_directory to: {}", output_directory);
         spawn_command(
             &self.config.executables.medcon,
-            &vec![
+            &[
                 "-f".to_string(),
-                self.working_directory.get_absolute_nifti_output(),
+                self.working_directory.get_absolute_nifti_output()?,
                 "-split3d".to_string(),
                 "-c".to_string(),
                 "dicom".to_string(),
             ],
-            Some(&self.working_directory.get_absolute_dicom_output_directory()),
+            Some(&output_directory),
+            &self.supervision(self.config.timeouts.export),
+            &|line| self.report_line(line),
         )?;
-        info!("Successfully convert NIfTI to DICOM");
-        info!(
-            "Creating output archive {}",
-            self.working_directory.get_dicom_filename()
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_default_is_valid() {
+        assert!(Options::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_options_set() {
+        let mut options = Options::default();
+        options.set("resolution", "0.5").unwrap();
+        options.set("two_step_cycles", "2").unwrap();
+        assert_eq!(options.isotropic_resolution, 0.5);
+        assert_eq!(options.two_step_cycles, 2);
+    }
+
+    #[test]
+    fn test_options_optional_args_only_emitted_when_set() {
+        let mut options = Options::default();
+        assert!(!options.to_args().contains(&"--sigma".to_string()));
+        options.set("sigma", "1.5").unwrap();
+        options.set("slice-thicknesses", "3,3.5").unwrap();
+        options.set("reconstruction-type", "TVL2").unwrap();
+        let args = options.to_args().join(" ");
+        assert!(args.contains("--sigma 1.5"));
+        assert!(args.contains("--slice-thicknesses 3 3.5"));
+        assert!(args.contains("--reconstruction-type TVL2"));
+    }
+
+    #[test]
+    fn test_options_with_base_directory() {
+        let mut options = Options::default();
+        options.set("target-stack", "nii/stack1.nii.gz").unwrap();
+        assert!(options
+            .set("reconstruction-space", "../outside.nii")
+            .is_err());
+        let options = options.with_base_directory("/app/data");
+        assert_eq!(
+            options.target_stack.as_deref(),
+            Some("/app/data/nii/stack1.nii.gz")
         );
-        Archive::new(&self.working_directory.get_absolute_dicom_output())
-            .create(self.working_directory.get_final_dicom_images().as_slice())?;
-        Ok(self.working_directory.get_absolute_dicom_output())
+    }
+
+    #[test]
+    fn test_options_set_rejects_invalid_values() {
+        let mut options = Options::default();
+        assert!(options.set("threshold", "1.5").is_err());
+        assert!(options.set("alpha", "abc").is_err());
+        assert!(options.set("unknown", "1").is_err());
+    }
+
+    #[test]
+    fn test_find_working_directory() {
+        let base = tempfile::tempdir().unwrap();
+        for name in ["scan-01hx", "other-01hx", "single-01hy"] {
+            fs::create_dir(base.path().join(name)).unwrap();
+            WorkingDirectory::new(&base.path().join(name).display().to_string())
+                .save_job(&Job::new())
+                .unwrap();
+        }
+        let base_directory = base.path().display().to_string();
+        let found = WorkingDirectory::find(&base_directory, "01HY").unwrap();
+        assert_eq!(found.directory, "single-01hy");
+        let Err(Error::AmbiguousJob(id, names)) = WorkingDirectory::find(&base_directory, "01hx")
+        else {
+            panic!("expected an ambiguous id");
+        };
+        assert_eq!(id, "01hx");
+        assert_eq!(names, vec!["other-01hx", "scan-01hx"]);
+        assert!(matches!(
+            WorkingDirectory::find(&base_directory, "01hz"),
+            Err(Error::UnknownJob(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_inputs_places_nifti_stacks() {
+        let inputs = tempfile::tempdir().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        let stacks = inputs.path().join("stacks");
+        fs::create_dir(&stacks).unwrap();
+        fs::write(stacks.join("axial.nii.gz"), "stack").unwrap();
+        fs::write(stacks.join("axial.json"), "{}").unwrap();
+        let coronal = inputs.path().join("coronal.nii.gz");
+        fs::write(&coronal, [0x1f, 0x8b, 0x08]).unwrap();
+
+        let working_directory = WorkingDirectory::from_inputs(
+            &[stacks.display().to_string(), coronal.display().to_string()],
+            &base.path().display().to_string(),
+            &ExtractLimits::default(),
+            &Deidentification::default(),
+        )
+        .unwrap();
+        assert!(working_directory.has_nifti_input());
+        assert!(working_directory.files.archive().list_files().is_empty());
+        assert_eq!(
+            working_directory.get_relative_nifti_images("/app").unwrap(),
+            vec!["/app/nii/001_axial.nii.gz", "/app/nii/coronal.nii.gz"]
+        );
+        working_directory.pick_stacks(&[2]).unwrap();
+        assert_eq!(
+            working_directory.get_relative_nifti_images("/app").unwrap(),
+            vec!["/app/nii/coronal.nii.gz"]
+        );
+        assert!(matches!(
+            working_directory.pick_stacks(&[3]),
+            Err(Error::InvalidSelection(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_inputs_keeps_dicom_next_to_nifti() {
+        let inputs = tempfile::tempdir().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        inventory::tests::write_slice(
+            &inputs.path().join("IM0001"),
+            "1.2",
+            "1.2.1",
+            [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+            &[],
+        );
+        fs::write(inputs.path().join("preview.nii.gz"), "preview").unwrap();
+
+        // A NIfTI preview next to DICOM does not replace the DICOM.
+        let working_directory = WorkingDirectory::from_inputs(
+            &[inputs.path().display().to_string()],
+            &base.path().display().to_string(),
+            &ExtractLimits::default(),
+            &Deidentification::default(),
+        )
+        .unwrap();
+        assert!(!working_directory.has_nifti_input());
+        assert_eq!(working_directory.inventory().unwrap().len(), 1);
+        assert!(working_directory.files.archive().nifti_files().is_empty());
+    }
+
+    #[test]
+    fn test_from_inputs_anonymizes_stack_names() {
+        let inputs = tempfile::tempdir().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        let stacks = inputs.path().join("stacks");
+        fs::create_dir(&stacks).unwrap();
+        fs::write(stacks.join("doe_jane_axial.nii.gz"), "stack").unwrap();
+        let coronal = inputs.path().join("doe_jane_coronal.nii.gz");
+        fs::write(&coronal, [0x1f, 0x8b, 0x08]).unwrap();
+        let deidentification = Deidentification {
+            enable: true,
+            ..Deidentification::default()
+        };
+
+        let working_directory = WorkingDirectory::from_inputs(
+            &[stacks.display().to_string(), coronal.display().to_string()],
+            &base.path().display().to_string(),
+            &ExtractLimits::default(),
+            &deidentification,
+        )
+        .unwrap();
+        let mut names: Vec<String> = working_directory
+            .files
+            .nii()
+            .nifti_files()
+            .iter()
+            .map(|stack| file_name(stack))
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["stack-1.nii.gz", "stack-2.nii.gz"]);
+    }
+
+    #[test]
+    fn test_stage_selected_series() {
+        let inputs = tempfile::tempdir().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        inventory::tests::write_slice(
+            &inputs.path().join("IM0001"),
+            "1.2",
+            "1.2.1",
+            [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+            &[],
+        );
+        inventory::tests::write_slice(
+            &inputs.path().join("IM0002"),
+            "1.3",
+            "1.2.2",
+            [0.0, 1.0, 0.0, 0.0, 0.0, -1.0],
+            &[],
+        );
+
+        let working_directory = WorkingDirectory::from_inputs(
+            &[inputs.path().display().to_string()],
+            &base.path().display().to_string(),
+            &ExtractLimits::default(),
+            &Deidentification::default(),
+        )
+        .unwrap();
+        let mut series = working_directory.inventory().unwrap();
+        series[0].selected = false;
+        let staging = working_directory
+            .stage_selected_series(&series)
+            .unwrap()
+            .unwrap();
+        let study = working_directory.study(&series).unwrap().unwrap();
+        assert_eq!(study.attributes["StudyInstanceUID"], "1.3");
+        let staged = inventory::scan(staging.path()).unwrap();
+        assert_eq!(staged.len(), 1);
+        assert_eq!(staged[0].series_instance_uid, "1.2.2");
+        // Only the selected series are staged, the input is left intact.
+        assert_eq!(working_directory.inventory().unwrap().len(), 2);
+
+        series[1].selected = false;
+        assert!(matches!(
+            working_directory.stage_selected_series(&series),
+            Err(Error::NoSeriesSelected)
+        ));
+        assert!(working_directory
+            .stage_selected_series(&[])
+            .unwrap()
+            .is_none());
     }
 }
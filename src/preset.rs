@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::niftymic::{Error, Options, Result};
+
+pub const FAST_PREVIEW: &str = "fast-preview";
+pub const STANDARD: &str = "standard";
+pub const HIGH_RES: &str = "high-res";
+
+/// Presets shipped with the crate, before any user-defined override.
+pub fn builtin() -> BTreeMap<String, Options> {
+    let mut presets = BTreeMap::new();
+    presets.insert(
+        FAST_PREVIEW.to_string(),
+        Options {
+            isotropic_resolution: 1.2,
+            two_step_cycles: 1,
+            ..Options::default()
+        },
+    );
+    presets.insert(STANDARD.to_string(), Options::default());
+    presets.insert(
+        HIGH_RES.to_string(),
+        Options {
+            isotropic_resolution: 0.5,
+            ..Options::default()
+        },
+    );
+    presets
+}
+
+/// Built-in presets merged with the `[presets.<name>]` sections of the configuration.
+pub fn all(config: &Config) -> BTreeMap<String, Options> {
+    let mut presets = builtin();
+    presets.extend(config.presets.clone());
+    presets
+}
+
+pub fn names(config: &Config) -> Vec<String> {
+    all(config).into_keys().collect()
+}
+
+pub fn resolve(name: &str, config: &Config) -> Result<Options> {
+    let options = all(config)
+        .remove(name)
+        .ok_or_else(|| Error::UnknownPreset(name.to_string()))?;
+    options.validate()?;
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_presets_are_valid() {
+        for (name, options) in builtin() {
+            assert!(options.validate().is_ok(), "{} is invalid", name);
+        }
+    }
+
+    #[test]
+    fn test_builtin_standard_is_default() {
+        assert_eq!(builtin()[STANDARD], Options::default());
+    }
+}
@@ -0,0 +1,143 @@
+use regex::Regex;
+use std::{fmt, sync::OnceLock};
+
+use crate::job::JobState;
+
+/// Structured event extracted from the pipeline and from the output of external tools.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    /// A pipeline step started; the state is the one reached when it completes.
+    Step(JobState),
+    /// dcm2niix converted a series to the given NIfTI file.
+    Converted(String),
+    /// NiftyMIC started a stage of a two-step cycle.
+    Cycle {
+        current: u64,
+        total: u64,
+        stage: String,
+    },
+    Iteration {
+        current: u64,
+        total: Option<u64>,
+    },
+}
+
+pub type ProgressCallback = std::sync::Arc<dyn Fn(Progress) + Send + Sync>;
+
+pub fn step_label(state: JobState) -> &'static str {
+    match state {
+        JobState::Received => "Received",
+        JobState::Inspected => "Inspecting DICOM series",
+        JobState::Converted => "Converting DICOM to NIfTI",
+        JobState::Masked => "Generating brain masks",
+        JobState::Reconstructed => "Reconstructing volume",
+        JobState::Exported => "Converting NIfTI to DICOM",
+        JobState::Failed => "Failed",
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Progress::Step(state) => write!(f, "{}", step_label(*state)),
+            Progress::Converted(file) => write!(f, "Converted {}", file),
+            Progress::Cycle {
+                current,
+                total,
+                stage,
+            } => write!(f, "Cycle {} of {}: {}", current, total, stage),
+            Progress::Iteration {
+                current,
+                total: Some(total),
+            } => write!(f, "Iteration {} of {}", current, total),
+            Progress::Iteration {
+                current,
+                total: None,
+            } => write!(f, "Iteration {}", current),
+        }
+    }
+}
+
+fn cycle_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"Cycle (\d+)/(\d+):\s*([^*]+?)[\s*]*$").unwrap())
+}
+
+fn iteration_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r"(?i)\biter(?:ation)?\s*[:#]?\s*(\d+)(?:\s*/\s*(\d+))?").unwrap()
+    })
+}
+
+fn converted_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"^Convert \d+ DICOM as (\S+)").unwrap())
+}
+
+/// Parses a line of NiftyMIC, dcm2niix or medcon output.
+pub fn parse_line(line: &str) -> Option<Progress> {
+    if let Some(captures) = cycle_regex().captures(line) {
+        return Some(Progress::Cycle {
+            current: captures[1].parse().ok()?,
+            total: captures[2].parse().ok()?,
+            stage: captures[3].to_string(),
+        });
+    }
+    if let Some(captures) = converted_regex().captures(line) {
+        return Some(Progress::Converted(captures[1].to_string()));
+    }
+    if let Some(captures) = iteration_regex().captures(line) {
+        return Some(Progress::Iteration {
+            current: captures[1].parse().ok()?,
+            total: captures
+                .get(2)
+                .and_then(|total| total.as_str().parse().ok()),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cycle() {
+        assert_eq!(
+            parse_line("*** Cycle 2/3: Slice-to-Volume Registration ***"),
+            Some(Progress::Cycle {
+                current: 2,
+                total: 3,
+                stage: "Slice-to-Volume Registration".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_iteration() {
+        assert_eq!(
+            parse_line("Iteration 4/10"),
+            Some(Progress::Iteration {
+                current: 4,
+                total: Some(10),
+            })
+        );
+        assert_eq!(
+            parse_line("iter: 7, cost = 1.2"),
+            Some(Progress::Iteration {
+                current: 7,
+                total: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_dcm2niix() {
+        assert_eq!(
+            parse_line("Convert 40 DICOM as /data/nii/T2_HASTE_3 (256x256x40x1)"),
+            Some(Progress::Converted("/data/nii/T2_HASTE_3".to_string()))
+        );
+        assert_eq!(parse_line("Chris Rorden's dcm2niiX version"), None);
+    }
+}
@@ -0,0 +1,292 @@
+use log::{debug, error};
+use std::{
+    collections::VecDeque,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+pub type Ticket = u64;
+
+struct Entry<T> {
+    ticket: Ticket,
+    item: T,
+}
+
+struct State<T> {
+    next_ticket: Ticket,
+    /// Owners in round-robin order, each with its own FIFO of pending entries.
+    owners: VecDeque<(i64, VecDeque<Entry<T>>)>,
+}
+
+impl<T> State<T> {
+    /// Tickets in the order they will be popped.
+    fn order(&self) -> Vec<Ticket> {
+        let mut order = Vec::new();
+        let rounds = self
+            .owners
+            .iter()
+            .map(|(_, entries)| entries.len())
+            .max()
+            .unwrap_or(0);
+        for round in 0..rounds {
+            for (_, entries) in &self.owners {
+                if let Some(entry) = entries.get(round) {
+                    order.push(entry.ticket);
+                }
+            }
+        }
+        order
+    }
+}
+
+/// In-process job queue that alternates between owners (e.g. Telegram chats) so a
+/// single owner submitting many jobs cannot starve the others.
+pub struct JobQueue<T> {
+    inner: Arc<(Mutex<State<T>>, Condvar)>,
+}
+
+impl<T> Clone for JobQueue<T> {
+    fn clone(&self) -> Self {
+        JobQueue {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for JobQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> JobQueue<T> {
+    pub fn new() -> Self {
+        JobQueue {
+            inner: Arc::new((
+                Mutex::new(State {
+                    next_ticket: 0,
+                    owners: VecDeque::new(),
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// Enqueues `item` and returns its ticket along with its 1-based position in line.
+    pub fn push(&self, owner: i64, item: T) -> (Ticket, usize) {
+        let (lock, condvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        let entry = Entry { ticket, item };
+        match state.owners.iter_mut().find(|(id, _)| *id == owner) {
+            Some((_, entries)) => entries.push_back(entry),
+            None => state.owners.push_back((owner, VecDeque::from([entry]))),
+        }
+        let position = Self::position_in(&state, ticket).unwrap();
+        condvar.notify_one();
+        (ticket, position)
+    }
+
+    /// 1-based position of `ticket`, or `None` once a worker picked it up.
+    pub fn position(&self, ticket: Ticket) -> Option<usize> {
+        let state = self.inner.0.lock().unwrap();
+        Self::position_in(&state, ticket)
+    }
+
+    /// Positions of every pending ticket of `owner`.
+    pub fn positions(&self, owner: i64) -> Vec<usize> {
+        let state = self.inner.0.lock().unwrap();
+        let Some((_, entries)) = state.owners.iter().find(|(id, _)| *id == owner) else {
+            return Vec::new();
+        };
+        entries
+            .iter()
+            .filter_map(|entry| Self::position_in(&state, entry.ticket))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        let state = self.inner.0.lock().unwrap();
+        state.owners.iter().map(|(_, entries)| entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every pending entry of `owner`.
+    pub fn remove_owner(&self, owner: i64) -> Vec<T> {
+        let mut state = self.inner.0.lock().unwrap();
+        let Some(index) = state.owners.iter().position(|(id, _)| *id == owner) else {
+            return Vec::new();
+        };
+        let (_, entries) = state.owners.remove(index).unwrap();
+        entries.into_iter().map(|entry| entry.item).collect()
+    }
+
+    /// Removes the next entry in fair order, if any.
+    pub fn try_pop(&self) -> Option<(Ticket, T)> {
+        let mut state = self.inner.0.lock().unwrap();
+        Self::pop_from(&mut state)
+    }
+
+    /// Blocks until an entry is available.
+    pub fn pop(&self) -> (Ticket, T) {
+        let (lock, condvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if let Some(entry) = Self::pop_from(&mut state) {
+                return entry;
+            }
+            state = condvar.wait(state).unwrap();
+        }
+    }
+
+    fn pop_from(state: &mut State<T>) -> Option<(Ticket, T)> {
+        let (owner, mut entries) = state.owners.pop_front()?;
+        let entry = entries.pop_front()?;
+        if !entries.is_empty() {
+            state.owners.push_back((owner, entries));
+        }
+        Some((entry.ticket, entry.item))
+    }
+
+    fn position_in(state: &State<T>, ticket: Ticket) -> Option<usize> {
+        state
+            .order()
+            .iter()
+            .position(|other| *other == ticket)
+            .map(|index| index + 1)
+    }
+}
+
+impl<T: Send + 'static> JobQueue<T> {
+    /// Spawns `workers` dedicated threads that run `handler` on each queued item.
+    /// A panicking `handler` does not stop its worker, `on_panic` is called with
+    /// the ticket instead.
+    pub fn start_workers<F, P>(&self, workers: usize, handler: F, on_panic: P)
+    where
+        F: Fn(Ticket, T) + Send + Sync + 'static,
+        P: Fn(Ticket) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let on_panic = Arc::new(on_panic);
+        for index in 0..workers.max(1) {
+            let queue = self.clone();
+            let handler = handler.clone();
+            let on_panic = on_panic.clone();
+            thread::Builder::new()
+                .name(format!("niftymic-worker-{}", index))
+                .spawn(move || loop {
+                    let (ticket, item) = queue.pop();
+                    debug!("Worker {} picked up job #{}", index, ticket);
+                    if let Err(panic) = catch_unwind(AssertUnwindSafe(|| handler(ticket, item))) {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|message| message.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        error!("Worker {} panicked on job #{}: {}", index, ticket, message);
+                        on_panic(ticket);
+                    }
+                })
+                .expect("Failed to spawn worker thread");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_queue_alternates_between_owners() {
+        let queue = JobQueue::new();
+        queue.push(1, "a1");
+        queue.push(1, "a2");
+        queue.push(1, "a3");
+        queue.push(2, "b1");
+        let (_, position) = queue.push(3, "c1");
+        assert_eq!(position, 3);
+        let order: Vec<_> = std::iter::from_fn(|| queue.try_pop().map(|(_, item)| item)).collect();
+        assert_eq!(order, vec!["a1", "b1", "c1", "a2", "a3"]);
+    }
+
+    #[test]
+    fn test_queue_positions() {
+        let queue = JobQueue::new();
+        let (first, _) = queue.push(1, ());
+        queue.push(1, ());
+        let (other, _) = queue.push(2, ());
+        assert_eq!(queue.position(other), Some(2));
+        assert_eq!(queue.positions(1), vec![1, 3]);
+        queue.try_pop();
+        assert_eq!(queue.position(first), None);
+        assert_eq!(queue.position(other), Some(1));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn test_queue_remove_owner() {
+        let queue = JobQueue::new();
+        queue.push(1, "a1");
+        let (other, _) = queue.push(2, "b1");
+        queue.push(1, "a2");
+        assert_eq!(queue.remove_owner(1), vec!["a1", "a2"]);
+        assert_eq!(queue.position(other), Some(1));
+        assert!(queue.remove_owner(1).is_empty());
+    }
+
+    #[test]
+    fn test_queue_workers() {
+        let queue = JobQueue::new();
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        queue.start_workers(
+            2,
+            move |_, item: u32| {
+                sender.lock().unwrap().send(item * 2).unwrap();
+            },
+            |_| {},
+        );
+        queue.push(1, 1);
+        queue.push(2, 2);
+        let mut results = vec![
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+        ];
+        results.sort();
+        assert_eq!(results, vec![2, 4]);
+    }
+
+    #[test]
+    fn test_queue_worker_survives_panic() {
+        let queue = JobQueue::new();
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let (panics, panicked) = mpsc::channel();
+        let panics = Mutex::new(panics);
+        queue.start_workers(
+            1,
+            move |_, item: u32| {
+                if item == 0 {
+                    panic!("bad job");
+                }
+                sender.lock().unwrap().send(item).unwrap();
+            },
+            move |ticket| panics.lock().unwrap().send(ticket).unwrap(),
+        );
+        let (ticket, _) = queue.push(1, 0);
+        queue.push(1, 7);
+        assert_eq!(
+            panicked.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ticket
+        );
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), 7);
+    }
+}
@@ -0,0 +1,291 @@
+use log::{info, warn};
+use std::{
+    fmt, fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{config::Retention, filemgr::FileManager, job::Job, niftymic::Result};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const MEGABYTE: u64 = 1024 * 1024;
+/// Time a directory may lack its job state file, e.g. while an upload is extracted.
+const STAGING_GRACE: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Finished,
+    Failed,
+    /// Queued, running or waiting for a selection.
+    Active,
+    /// No job state yet, the inputs are being staged or staging crashed.
+    Staging,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Expired,
+    Failed,
+    Abandoned,
+    Size,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Expired => write!(f, "expired"),
+            Reason::Failed => write!(f, "failed"),
+            Reason::Abandoned => write!(f, "abandoned"),
+            Reason::Size => write!(f, "size limit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub created_at: SystemTime,
+    pub size: u64,
+    pub status: Status,
+}
+
+#[derive(Debug, Clone)]
+pub struct Removal {
+    pub entry: Entry,
+    pub reason: Reason,
+}
+
+impl Entry {
+    fn from_file_manager(files: &FileManager) -> Option<Entry> {
+        let created_at = files.created_at()?;
+        let status = match Job::load(files.job_file()) {
+            Ok(job) if job.is_finished() => Status::Finished,
+            Ok(job) if !job.is_failed() => Status::Active,
+            Ok(_) => Status::Failed,
+            Err(_) if !files.job_file().exists() => Status::Staging,
+            Err(_) => Status::Failed,
+        };
+        Some(Entry {
+            path: files.root().as_path().to_path_buf(),
+            created_at,
+            size: files.size(),
+            status,
+        })
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.created_at).unwrap_or_default()
+    }
+}
+
+/// Working directories under `base_directory`, recognised by the ULID in their name.
+pub fn scan(base_directory: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(base_directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            entries.extend(Entry::from_file_manager(&FileManager::new(path)));
+        }
+    }
+    entries.sort_by_key(|entry| entry.created_at);
+    Ok(entries)
+}
+
+/// Picks the entries to remove: expired ones first, then the oldest until the
+/// total size fits in the limit. Active jobs are only removed once abandoned,
+/// directories still being staged never.
+pub fn select(entries: &[Entry], retention: &Retention, now: SystemTime) -> Vec<Removal> {
+    let mut removals = Vec::new();
+    let mut kept = Vec::new();
+    for entry in entries {
+        let status = match entry.status {
+            Status::Staging if entry.age(now) > STAGING_GRACE => Status::Failed,
+            status => status,
+        };
+        let max_age = match status {
+            Status::Finished => retention.max_age_days,
+            Status::Failed => retention.keep_failed_days.or(retention.max_age_days),
+            Status::Active => retention.keep_active_days,
+            Status::Staging => None,
+        };
+        let reason = match status {
+            Status::Failed => Reason::Failed,
+            Status::Active => Reason::Abandoned,
+            _ => Reason::Expired,
+        };
+        match max_age {
+            Some(days) if entry.age(now) > DAY * days as u32 => removals.push(Removal {
+                entry: entry.clone(),
+                reason,
+            }),
+            _ => kept.push(entry),
+        }
+    }
+
+    if let Some(max_total_size_mb) = retention.max_total_size_mb {
+        let limit = max_total_size_mb.saturating_mul(MEGABYTE);
+        let mut total: u64 = kept.iter().map(|entry| entry.size).sum();
+        kept.sort_by_key(|entry| entry.created_at);
+        for entry in kept {
+            if total <= limit {
+                break;
+            }
+            if !matches!(entry.status, Status::Active | Status::Staging) {
+                total -= entry.size;
+                removals.push(Removal {
+                    entry: entry.clone(),
+                    reason: Reason::Size,
+                });
+            }
+        }
+    }
+    removals
+}
+
+/// Applies the retention policy to `base_directory`, only listing what would
+/// be removed when `dry_run` is set.
+pub fn collect(base_directory: &str, retention: &Retention, dry_run: bool) -> Result<Vec<Removal>> {
+    let removals = select(&scan(base_directory)?, retention, SystemTime::now());
+    if !dry_run {
+        for removal in &removals {
+            info!(
+                "Removing {} ({})",
+                removal.entry.path.display(),
+                removal.reason
+            );
+            if let Err(error) = fs::remove_dir_all(&removal.entry.path) {
+                warn!(
+                    "Failed to remove {}: {}",
+                    removal.entry.path.display(),
+                    error
+                );
+            }
+        }
+    }
+    Ok(removals)
+}
+
+/// Runs `collect` every `retention.interval_minutes` on a dedicated thread.
+pub fn start_cleaner(base_directory: &str, retention: &Retention) {
+    let Some(interval) = retention.interval_minutes else {
+        return;
+    };
+    let base_directory = base_directory.to_string();
+    let retention = retention.clone();
+    std::thread::Builder::new()
+        .name("niftymic-cleaner".to_string())
+        .spawn(move || loop {
+            if let Err(error) = collect(&base_directory, &retention, false) {
+                warn!("Failed to clean {}: {}", base_directory, error);
+            }
+            std::thread::sleep(Duration::from_secs(interval.max(1).saturating_mul(60)));
+        })
+        .expect("Failed to spawn cleaner thread");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, days: u64, size: u64, status: Status, now: SystemTime) -> Entry {
+        Entry {
+            path: PathBuf::from(name),
+            created_at: now - DAY * days as u32,
+            size: size * MEGABYTE,
+            status,
+        }
+    }
+
+    fn names(removals: &[Removal]) -> Vec<(String, Reason)> {
+        removals
+            .iter()
+            .map(|removal| (removal.entry.path.display().to_string(), removal.reason))
+            .collect()
+    }
+
+    #[test]
+    fn test_select_by_age() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("old-finished", 40, 1, Status::Finished, now),
+            entry("old-running", 40, 1, Status::Active, now),
+            entry("failed", 3, 1, Status::Failed, now),
+            entry("recent", 1, 1, Status::Finished, now),
+        ];
+        let retention = Retention {
+            max_age_days: Some(30),
+            keep_failed_days: Some(2),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(&select(&entries, &retention, now)),
+            vec![
+                ("old-finished".to_string(), Reason::Expired),
+                ("failed".to_string(), Reason::Failed),
+            ]
+        );
+        assert!(select(&entries, &Retention::default(), now).is_empty());
+    }
+
+    #[test]
+    fn test_select_by_size() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("oldest", 5, 50, Status::Active, now),
+            entry("older", 4, 50, Status::Finished, now),
+            entry("old", 3, 50, Status::Failed, now),
+            entry("new", 1, 50, Status::Finished, now),
+        ];
+        let retention = Retention {
+            max_total_size_mb: Some(120),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(&select(&entries, &retention, now)),
+            vec![
+                ("older".to_string(), Reason::Size),
+                ("old".to_string(), Reason::Size),
+            ]
+        );
+        let retention = Retention {
+            max_total_size_mb: Some(u64::MAX),
+            ..Retention::default()
+        };
+        assert!(select(&entries, &retention, now).is_empty());
+    }
+
+    #[test]
+    fn test_scan_uses_ulid_timestamp() {
+        let base = tempfile::tempdir().unwrap();
+        let files = FileManager::generate(base.path(), "scan");
+        files.create().unwrap();
+        fs::create_dir(base.path().join("not-a-job")).unwrap();
+        let entries = scan(&base.path().display().to_string()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].status, Status::Staging);
+    }
+
+    #[test]
+    fn test_select_staging_and_abandoned() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("stale-upload", 2, 50, Status::Staging, now),
+            entry("paused", 20, 1, Status::Active, now),
+            entry("running", 1, 1, Status::Active, now),
+            entry("upload", 0, 100, Status::Staging, now),
+        ];
+        let retention = Retention {
+            keep_failed_days: Some(1),
+            keep_active_days: Some(14),
+            max_total_size_mb: Some(10),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(&select(&entries, &retention, now)),
+            vec![
+                ("stale-upload".to_string(), Reason::Failed),
+                ("paused".to_string(), Reason::Abandoned),
+            ]
+        );
+    }
+}
@@ -1,10 +1,15 @@
-use log::debug;
+use log::{debug, warn};
 use std::{
-    io::{BufRead, BufReader},
-    process::{Command, Output, Stdio},
+    collections::VecDeque,
+    fmt,
+    io::{BufRead, BufReader, Read},
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::config::Config;
+use crate::cancel::CancellationToken;
 use crate::niftymic::*;
 
 pub fn print_output(output: &Output) {
@@ -16,74 +21,327 @@ pub fn print_output(output: &Output) {
     }
 }
 
-pub fn spawn_command(binary: &str, args: &Vec<String>, current_dir: Option<&str>) -> Result<()> {
-    let current_dir = match current_dir {
-        Some(directory) => directory,
-        None => ".",
-    };
+/// Number of trailing stdout and stderr lines kept for error reports.
+const OUTPUT_TAIL_LINES: usize = 20;
+/// How often a running command is checked for timeout and cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    Code(i32),
+    Signal(i32),
+    Unknown,
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Code(code) => write!(f, "exit code {}", code),
+            ExitReason::Signal(signal) => write!(f, "signal {}", signal),
+            ExitReason::Unknown => write!(f, "unknown status"),
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitReason {
+    fn from(status: ExitStatus) -> Self {
+        if let Some(code) = status.code() {
+            return ExitReason::Code(code);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return ExitReason::Signal(signal);
+            }
+        }
+        ExitReason::Unknown
+    }
+}
+
+/// External command that did not exit successfully.
+#[derive(Debug, Clone)]
+pub struct CommandFailure {
+    pub program: String,
+    pub args: Vec<String>,
+    pub reason: ExitReason,
+    /// Last lines written to stdout.
+    pub stdout: Vec<String>,
+    /// Last lines written to stderr.
+    pub stderr: Vec<String>,
+}
+
+impl CommandFailure {
+    /// Last `lines` lines of stderr, falling back to stdout when stderr is empty.
+    pub fn tail(&self, lines: usize) -> Vec<String> {
+        let output = if self.stderr.is_empty() {
+            &self.stdout
+        } else {
+            &self.stderr
+        };
+        output[output.len().saturating_sub(lines)..].to_vec()
+    }
+
+    /// Full report with the command line and the captured output.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "{}\ncommand: {} {}",
+            self,
+            self.program,
+            self.args.join(" ")
+        );
+        for (name, lines) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if !lines.is_empty() {
+                report.push_str(&format!("\n--- {} (last {} lines) ---", name, lines.len()));
+                for line in lines {
+                    report.push('\n');
+                    report.push_str(line);
+                }
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed with {}", self.program, self.reason)
+    }
+}
+
+fn push_tail(tail: &mut VecDeque<String>, line: String) {
+    if tail.len() == OUTPUT_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
+
+/// Hands every line of `reader` to `on_line` until EOF. Lines may not be UTF-8,
+/// the pipe is drained either way so the child never blocks on a full pipe.
+fn for_each_line<R: Read>(reader: R, mut on_line: impl FnMut(String)) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        on_line(text.trim_end_matches(['\n', '\r']).to_string());
+        line.clear();
+    }
+}
+
+/// Limits applied to an external command while it runs.
+#[derive(Debug, Clone, Default)]
+pub struct Supervision {
+    pub timeout: Option<Duration>,
+    pub cancel: CancellationToken,
+}
+
+impl Supervision {
+    pub fn new(timeout: Option<Duration>, cancel: &CancellationToken) -> Self {
+        Supervision {
+            timeout,
+            cancel: cancel.clone(),
+        }
+    }
+}
+
+enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+}
+
+fn forward_lines<R: Read + Send + 'static>(
+    reader: R,
+    sender: mpsc::Sender<OutputLine>,
+    wrap: fn(String) -> OutputLine,
+) {
+    thread::spawn(move || {
+        for_each_line(reader, |line| {
+            let _ = sender.send(wrap(line));
+        })
+    });
+}
+
+/// Runs `binary` to completion, handing every stdout line to `on_line`.
+/// The process is killed when `supervision` times out or is cancelled.
+pub fn spawn_command(
+    binary: &str,
+    args: &[String],
+    current_dir: Option<&str>,
+    supervision: &Supervision,
+    on_line: &dyn Fn(&str),
+) -> Result<()> {
+    let current_dir = current_dir.unwrap_or(".");
     debug!("{} {}", binary, args.join(" "));
+    if supervision.cancel.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
     let mut cmd = Command::new(binary)
         .args(args)
         .current_dir(current_dir)
         .stdout(Stdio::piped())
-        .spawn()?;
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| Error::FailedToSpawn(binary.to_string(), error))?;
 
-    {
-        let stdout = cmd.stdout.as_mut().unwrap();
-        let stdout_reader = BufReader::new(stdout);
-        let stdout_lines = stdout_reader.lines();
+    // Readers are detached so a killed process whose children still hold the pipes
+    // cannot keep us waiting.
+    let (sender, receiver) = mpsc::channel();
+    forward_lines(
+        cmd.stdout.take().unwrap(),
+        sender.clone(),
+        OutputLine::Stdout,
+    );
+    forward_lines(cmd.stderr.take().unwrap(), sender, OutputLine::Stderr);
 
-        for line in stdout_lines {
-            if let Ok(log_line) = line {
+    let started = Instant::now();
+    let mut stdout_tail = VecDeque::new();
+    let mut stderr_tail = VecDeque::new();
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(OutputLine::Stdout(log_line)) => {
+                debug!("{}", log_line);
+                on_line(&log_line);
+                push_tail(&mut stdout_tail, log_line);
+            }
+            Ok(OutputLine::Stderr(log_line)) => {
                 debug!("{}", log_line);
+                push_tail(&mut stderr_tail, log_line);
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-    }
-    if let Ok(exit_status) = cmd.wait() {
-        if exit_status.success() {
-            return Ok(());
+        let interruption = if supervision.cancel.is_cancelled() {
+            Some(Error::Cancelled)
+        } else {
+            supervision
+                .timeout
+                .filter(|timeout| started.elapsed() >= *timeout)
+                .map(|timeout| Error::TimedOut(binary.to_string(), timeout))
+        };
+        if let Some(error) = interruption {
+            warn!("Stopping {}: {}", binary, error);
+            cmd.kill()?;
+            cmd.wait()?;
+            return Err(error);
         }
     }
-    Err(Error::CommandFailed("Mask generation failed".to_string()))
-}
 
-pub struct DockerWrapper {
-    pub executable: String,
-    pub working_directory: String,
-    pub image: String,
+    let exit_status = cmd.wait()?;
+    if exit_status.success() {
+        return Ok(());
+    }
+    Err(Error::CommandFailed(Box::new(CommandFailure {
+        program: binary.to_string(),
+        args: args.to_vec(),
+        reason: exit_status.into(),
+        stdout: stdout_tail.into(),
+        stderr: stderr_tail.into(),
+    })))
 }
 
-impl DockerWrapper {
-    pub fn new(executable: &str, working_directory: &str, image: &str) -> DockerWrapper {
-        DockerWrapper {
-            executable: executable.to_string(),
-            working_directory: working_directory.to_string(),
-            image: image.to_string(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_command_success() {
+        let lines = std::cell::RefCell::new(Vec::new());
+        spawn_command(
+            "sh",
+            &["-c".to_string(), "echo one; echo two".to_string()],
+            None,
+            &Supervision::default(),
+            &|line| lines.borrow_mut().push(line.to_string()),
+        )
+        .unwrap();
+        assert_eq!(lines.into_inner(), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn test_spawn_command_non_utf8_output() {
+        let lines = std::cell::RefCell::new(Vec::new());
+        spawn_command(
+            "sh",
+            &[
+                "-c".to_string(),
+                "printf 'caf\\351\\n'; seq 1 20000 >&2; echo done".to_string(),
+            ],
+            None,
+            &Supervision::new(Some(Duration::from_secs(10)), &CancellationToken::new()),
+            &|line| lines.borrow_mut().push(line.to_string()),
+        )
+        .unwrap();
+        assert_eq!(lines.into_inner(), vec!["caf\u{fffd}", "done"]);
     }
 
-    pub fn from_config(config: &Config) -> DockerWrapper {
-        DockerWrapper::new(
-            &config.executables.docker,
-            &config.docker.working_directory,
-            &config.docker.image,
+    #[test]
+    fn test_spawn_command_failure_captures_output() {
+        let error = spawn_command(
+            "sh",
+            &[
+                "-c".to_string(),
+                "echo out; echo err >&2; exit 3".to_string(),
+            ],
+            None,
+            &Supervision::default(),
+            &|_| {},
         )
+        .unwrap_err();
+        let Error::CommandFailed(failure) = error else {
+            panic!("unexpected error {}", error);
+        };
+        assert_eq!(failure.program, "sh");
+        assert_eq!(failure.reason, ExitReason::Code(3));
+        assert_eq!(failure.stdout, vec!["out"]);
+        assert_eq!(failure.stderr, vec!["err"]);
+        assert_eq!(failure.tail(5), vec!["err"]);
     }
 
-    pub fn get_run_command_args(&self, working_directory: &str) -> Vec<String> {
-        let mut args = Vec::new();
-        args.push("run".to_string());
-        args.push("--rm".to_string());
-        args.push("-v".to_string());
-        args.push(format!("{}:{}", working_directory, self.working_directory));
-        args.push(self.image.to_string());
-        args
+    #[test]
+    fn test_spawn_command_missing_binary() {
+        let error = spawn_command(
+            "/nonexistent/binary",
+            &[],
+            None,
+            &Supervision::default(),
+            &|_| {},
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::FailedToSpawn(_, _)));
     }
 
-    pub fn run(&self, command: &str, args: &Vec<String>, working_directory: &str) -> Result<()> {
-        let mut command_line = self.get_run_command_args(working_directory).clone();
-        command_line.push(command.to_string());
-        command_line.append(&mut args.clone());
-        spawn_command(&self.executable, &command_line, None)
+    #[test]
+    fn test_spawn_command_timeout() {
+        let started = Instant::now();
+        let supervision =
+            Supervision::new(Some(Duration::from_millis(300)), &CancellationToken::new());
+        let error = spawn_command(
+            "sh",
+            &["-c".to_string(), "sleep 10; echo done".to_string()],
+            None,
+            &supervision,
+            &|_| {},
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::TimedOut(_, _)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_command_cancelled() {
+        let cancel = CancellationToken::new();
+        let supervision = Supervision::new(None, &cancel);
+        let error = spawn_command(
+            "sh",
+            &["-c".to_string(), "echo started; sleep 10".to_string()],
+            None,
+            &supervision,
+            &|_| cancel.cancel(),
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::Cancelled));
     }
 }
@@ -0,0 +1,268 @@
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::tags;
+use dicom_object::{DefaultDicomObject, OpenFileOptions};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    dicom::{floats, new_uid, normal, string},
+    inventory::Series,
+    niftymic::{Error, Result},
+};
+
+/// Written to SeriesDescription of the reconstructed series.
+pub const SERIES_DESCRIPTION: &str = "NiftyMIC SRR";
+
+/// Patient, study and frame of reference attributes carried from the input to
+/// the reconstructed series.
+const ATTRIBUTES: &[(&str, Tag, VR)] = &[
+    ("PatientName", tags::PATIENT_NAME, VR::PN),
+    ("PatientID", tags::PATIENT_ID, VR::LO),
+    ("PatientBirthDate", tags::PATIENT_BIRTH_DATE, VR::DA),
+    ("PatientSex", tags::PATIENT_SEX, VR::CS),
+    ("PatientAge", tags::PATIENT_AGE, VR::AS),
+    ("StudyInstanceUID", tags::STUDY_INSTANCE_UID, VR::UI),
+    ("StudyID", tags::STUDY_ID, VR::SH),
+    ("StudyDate", tags::STUDY_DATE, VR::DA),
+    ("StudyTime", tags::STUDY_TIME, VR::TM),
+    ("StudyDescription", tags::STUDY_DESCRIPTION, VR::LO),
+    ("AccessionNumber", tags::ACCESSION_NUMBER, VR::SH),
+    (
+        "ReferringPhysicianName",
+        tags::REFERRING_PHYSICIAN_NAME,
+        VR::PN,
+    ),
+    ("FrameOfReferenceUID", tags::FRAME_OF_REFERENCE_UID, VR::UI),
+    (
+        "PositionReferenceIndicator",
+        tags::POSITION_REFERENCE_INDICATOR,
+        VR::LO,
+    ),
+];
+
+fn open(path: &Path) -> Result<DefaultDicomObject> {
+    OpenFileOptions::new()
+        .open_file(path)
+        .map_err(|error| Error::FailedToReadDicom(path.display().to_string(), error.to_string()))
+}
+
+/// Patient and study context of the input, by attribute keyword.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Study {
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Study {
+    /// Reads the context from the header of the DICOM file at `path`.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Study> {
+        let path = path.as_ref();
+        let object = OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(path)
+            .map_err(|error| {
+                Error::FailedToReadDicom(path.display().to_string(), error.to_string())
+            })?;
+        Ok(Study {
+            attributes: ATTRIBUTES
+                .iter()
+                .filter_map(|(keyword, tag, _)| {
+                    string(&object, *tag).map(|value| (keyword.to_string(), value))
+                })
+                .collect(),
+        })
+    }
+
+    /// Context of the first selected series of `series`, the first series when
+    /// none is selected.
+    pub fn from_series(series: &[Series]) -> Result<Option<Study>> {
+        series
+            .iter()
+            .find(|series| series.selected)
+            .or(series.first())
+            .and_then(|series| series.files.first())
+            .map(Study::read)
+            .transpose()
+    }
+
+    /// Replaces the context of `object`, attributes missing from the study are
+    /// emptied.
+    fn apply(&self, object: &mut DefaultDicomObject) {
+        for (keyword, tag, vr) in ATTRIBUTES {
+            let value = match self.attributes.get(*keyword) {
+                Some(value) => PrimitiveValue::from(value.as_str()),
+                None => PrimitiveValue::Empty,
+            };
+            object.put(DataElement::new(*tag, *vr, value));
+        }
+    }
+}
+
+/// First slice position and unit normal of the slice in `object`.
+fn geometry(object: &DefaultDicomObject) -> Option<([f64; 3], [f64; 3])> {
+    let position = floats(object, tags::IMAGE_POSITION_PATIENT)?
+        .try_into()
+        .ok()?;
+    let normal = normal(
+        floats(object, tags::IMAGE_ORIENTATION_PATIENT)?
+            .try_into()
+            .ok()?,
+    );
+    let norm = normal.iter().map(|value| value * value).sum::<f64>().sqrt();
+    (norm > 0.0).then(|| (position, normal.map(|value| value / norm)))
+}
+
+/// Turns the slices converted from the reconstruction, in file name order, into
+/// a new series of `study` when known. Slices are numbered from 1 and, given a
+/// `spacing`, positioned that many millimetres apart along the normal of the
+/// first one. Those positions are not in the frame of the source, the series
+/// gets a FrameOfReferenceUID of its own then.
+pub fn rewrite_series(files: &[String], study: Option<&Study>, spacing: Option<f64>) -> Result<()> {
+    let series_instance_uid = new_uid();
+    let frame_of_reference_uid = spacing.map(|_| new_uid());
+    let mut origin = None;
+    for (index, file) in files.iter().enumerate() {
+        let path = Path::new(file);
+        let mut object = open(path)?;
+        if let Some(study) = study {
+            study.apply(&mut object);
+        }
+        object.put(DataElement::new(
+            tags::SERIES_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from(series_instance_uid.as_str()),
+        ));
+        if let Some(frame_of_reference_uid) = &frame_of_reference_uid {
+            object.put(DataElement::new(
+                tags::FRAME_OF_REFERENCE_UID,
+                VR::UI,
+                PrimitiveValue::from(frame_of_reference_uid.as_str()),
+            ));
+        }
+        object.put(DataElement::new(
+            tags::SERIES_DESCRIPTION,
+            VR::LO,
+            PrimitiveValue::from(SERIES_DESCRIPTION),
+        ));
+        object.put(DataElement::new(
+            tags::INSTANCE_NUMBER,
+            VR::IS,
+            PrimitiveValue::from((index + 1).to_string()),
+        ));
+        if origin.is_none() {
+            origin = geometry(&object);
+        }
+        match origin.zip(spacing) {
+            Some(((first, normal), spacing)) => {
+                let offset = spacing * index as f64;
+                let position: Vec<f64> = (0..3)
+                    .map(|axis| first[axis] + offset * normal[axis])
+                    .collect();
+                object.put(DataElement::new(
+                    tags::IMAGE_POSITION_PATIENT,
+                    VR::DS,
+                    PrimitiveValue::F64(position.into_iter().collect()),
+                ));
+            }
+            None => debug!("Keeping the position of {}", file),
+        }
+        object.write_to_file(path).map_err(|error| {
+            Error::FailedToWriteDicom(path.display().to_string(), error.to_string())
+        })?;
+    }
+    info!(
+        "Rewrote {} slices of series {}",
+        files.len(),
+        series_instance_uid
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::tests::write_slice;
+
+    #[test]
+    fn test_rewrite_series() {
+        let directory = tempfile::tempdir().unwrap();
+        let input = directory.path().join("input");
+        write_slice(
+            &input,
+            "1.2",
+            "1.2.1",
+            [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+            &[
+                DataElement::new(tags::PATIENT_NAME, VR::PN, "Doe^Jane"),
+                DataElement::new(tags::ACCESSION_NUMBER, VR::SH, "A42"),
+                DataElement::new(tags::FRAME_OF_REFERENCE_UID, VR::UI, "1.2.4"),
+            ],
+        );
+        let mut series = crate::inventory::scan(directory.path()).unwrap();
+        series[0].selected = false;
+        let study = Study::from_series(&series).unwrap().unwrap();
+        assert_eq!(study.attributes["StudyInstanceUID"], "1.2");
+        assert_eq!(study.attributes["AccessionNumber"], "A42");
+        assert_eq!(study.attributes["FrameOfReferenceUID"], "1.2.4");
+
+        // Slices as written by medcon: own study and series, all at one position.
+        let files: Vec<String> = (0..3)
+            .map(|index| {
+                let path = directory.path().join(format!("m{:03}.dcm", index));
+                write_slice(
+                    &path,
+                    "9.9",
+                    "9.9.9",
+                    [0.0, 1.0, 0.0, 0.0, 0.0, -1.0],
+                    &[
+                        DataElement::new(tags::PATIENT_NAME, VR::PN, "medcon"),
+                        DataElement::new(
+                            tags::IMAGE_POSITION_PATIENT,
+                            VR::DS,
+                            PrimitiveValue::F64([10.0, 0.0, 0.0].into_iter().collect()),
+                        ),
+                    ],
+                );
+                path.display().to_string()
+            })
+            .collect();
+        rewrite_series(&files, Some(&study), Some(0.8)).unwrap();
+
+        let slices: Vec<DefaultDicomObject> = files
+            .iter()
+            .map(|file| open(Path::new(file)).unwrap())
+            .collect();
+        for (index, slice) in slices.iter().enumerate() {
+            assert_eq!(string(slice, tags::STUDY_INSTANCE_UID).unwrap(), "1.2");
+            assert_eq!(string(slice, tags::PATIENT_NAME).unwrap(), "Doe^Jane");
+            assert_eq!(string(slice, tags::ACCESSION_NUMBER).unwrap(), "A42");
+            assert_eq!(string(slice, tags::PATIENT_ID), None);
+            assert_eq!(
+                string(slice, tags::SERIES_DESCRIPTION).unwrap(),
+                SERIES_DESCRIPTION
+            );
+            assert_eq!(
+                string(slice, tags::INSTANCE_NUMBER).unwrap(),
+                (index + 1).to_string()
+            );
+            let [x, y, z]: [f64; 3] = floats(slice, tags::IMAGE_POSITION_PATIENT)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            assert!((x - (10.0 - 0.8 * index as f64)).abs() < 1e-6);
+            assert!(y.abs() < 1e-6 && z.abs() < 1e-6);
+        }
+        let series_uid = string(&slices[0], tags::SERIES_INSTANCE_UID).unwrap();
+        assert_ne!(series_uid, "9.9.9");
+        assert!(slices
+            .iter()
+            .all(|slice| string(slice, tags::SERIES_INSTANCE_UID).unwrap() == series_uid));
+        // The medcon slices are positioned by spacing only, not in the source frame.
+        let frame_uid = string(&slices[0], tags::FRAME_OF_REFERENCE_UID).unwrap();
+        assert_ne!(frame_uid, "1.2.4");
+        assert!(slices
+            .iter()
+            .all(|slice| string(slice, tags::FRAME_OF_REFERENCE_UID).unwrap() == frame_uid));
+    }
+}